@@ -0,0 +1,25 @@
+#!/bin/bash
+#
+# Writes esp/efi/boot/kernel.sym from the kernel's PDB: one "<rva> <function>" line per
+# function, sorted by address. The kernel loads it at boot to symbolize backtraces.
+
+set -e
+cd $(dirname $0)
+
+PDB=${1:-../target/x86_64-unknown-uefi/debug/KitsuneOS.pdb}
+
+{
+    llvm-pdbutil dump --section-headers "$PDB"
+    llvm-pdbutil dump --publics "$PDB"
+} | awk '
+    function hex(s,    i, v) {
+        v = 0
+        for (i = 1; i <= length(s); i++)
+            v = v * 16 + index("0123456789abcdef", tolower(substr(s, i, 1))) - 1
+        return v
+    }
+    /SECTION HEADER #/ { section = substr($3, 2) + 0 }
+    /virtual address/ { va[section] = hex($1) }
+    /S_PUB32/ { match($0, /`[^`]*`/); name = substr($0, RSTART + 1, RLENGTH - 2) }
+    /flags = function/ { split($NF, loc, ":"); printf "%08x %s\n", va[loc[1] + 0] + loc[2], name }
+' | llvm-cxxfilt | sort > esp/efi/boot/kernel.sym
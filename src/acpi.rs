@@ -0,0 +1,437 @@
+use core::fmt;
+use spin::Once;
+use uefi::system::with_config_table;
+use uefi::table::cfg::ConfigTableEntry;
+use x86_64::PhysAddr;
+
+use crate::println;
+use crate::virtualmapper::phys_to_virt;
+
+const MAX_CPUS: usize = 64;
+const MAX_IOAPICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+const MAX_NMIS: usize = 16;
+const MAX_PCI_SEGMENTS: usize = 4;
+const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    BadChecksum(&'static str),
+    BadSignature(&'static str),
+    // The length in the header does not even cover the header
+    BadLength(&'static str),
+    MissingTable(&'static str),
+}
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::BadChecksum(table) => write!(f, "bad {} checksum", table),
+            AcpiError::BadSignature(table) => write!(f, "bad {} signature", table),
+            AcpiError::BadLength(table) => write!(f, "{} shorter than its header", table),
+            AcpiError::MissingTable(table) => write!(f, "no {} table", table),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+// An ISA IRQ that is wired to a different GSI or with non-default polarity/trigger.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // 0xFF means every processor
+    pub processor_id: u8,
+    pub lint: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub pm_timer_block: u32,
+    pub pm_timer_32bit: bool,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub address: u64,
+    pub number: u8,
+    pub min_tick: u16,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct PciSegment {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub lapic_address: u64,
+    pub legacy_pics: bool,
+    pub cpus: [Option<LocalApic>; MAX_CPUS],
+    pub ioapics: [Option<IoApicInfo>; MAX_IOAPICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+    pub lapic_nmis: [Option<LocalApicNmi>; MAX_NMIS],
+    pub nmi_sources: [Option<NmiSource>; MAX_NMIS],
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub pci_segments: [Option<PciSegment>; MAX_PCI_SEGMENTS],
+}
+
+static ACPI: Once<AcpiInfo> = Once::new();
+
+// Looks up the RSDP in the UEFI configuration table, preferring the ACPI 2.0 entry.
+// Must be called before boot services are exited.
+pub fn find_rsdp() -> Option<u64> {
+    with_config_table(|entries| {
+        let find = |guid| {
+            entries
+                .iter()
+                .find(|e| e.guid == guid)
+                .map(|e| e.address as u64)
+        };
+        find(ConfigTableEntry::ACPI2_GUID).or_else(|| find(ConfigTableEntry::ACPI_GUID))
+    })
+}
+
+// A physical table read through the direct map; fields are read unaligned.
+#[derive(Clone, Copy)]
+struct Table {
+    phys: u64,
+    len: usize,
+}
+impl Table {
+    fn ptr(&self, offset: usize) -> *const u8 {
+        phys_to_virt(PhysAddr::new(self.phys + offset as u64)).as_ptr::<u8>()
+    }
+    fn u8(&self, offset: usize) -> u8 {
+        unsafe { self.ptr(offset).read() }
+    }
+    fn u16(&self, offset: usize) -> u16 {
+        unsafe { (self.ptr(offset) as *const u16).read_unaligned() }
+    }
+    fn u32(&self, offset: usize) -> u32 {
+        unsafe { (self.ptr(offset) as *const u32).read_unaligned() }
+    }
+    fn u64(&self, offset: usize) -> u64 {
+        unsafe { (self.ptr(offset) as *const u64).read_unaligned() }
+    }
+    fn signature(&self) -> [u8; 4] {
+        [self.u8(0), self.u8(1), self.u8(2), self.u8(3)]
+    }
+    fn checksum_ok(&self) -> bool {
+        (0..self.len).fold(0u8, |sum, i| sum.wrapping_add(self.u8(i))) == 0
+    }
+    // Opens the SDT at `phys`, checking its checksum against the length in its header.
+    fn sdt(phys: u64) -> Result<Table, AcpiError> {
+        let header = Table {
+            phys,
+            len: SDT_HEADER_SIZE,
+        };
+        let table = Table {
+            phys,
+            len: header.u32(4) as usize,
+        };
+        if table.len < SDT_HEADER_SIZE {
+            return Err(AcpiError::BadLength("SDT"));
+        }
+        if !table.checksum_ok() {
+            return Err(AcpiError::BadChecksum("SDT"));
+        }
+        Ok(table)
+    }
+}
+
+fn flags_polarity(flags: u16) -> (bool, bool) {
+    // MPS INTI flags: polarity in bits 0-1 (3 = active low), trigger in bits 2-3 (3 = level)
+    (flags & 0b11 == 0b11, (flags >> 2) & 0b11 == 0b11)
+}
+
+fn push<T: Copy>(slots: &mut [Option<T>], value: T, what: &str) {
+    match slots.iter_mut().find(|s| s.is_none()) {
+        Some(slot) => *slot = Some(value),
+        None => println!("[WARN] ACPI: too many {} entries, ignoring some", what),
+    }
+}
+
+fn parse_madt(madt: Table, info: &mut AcpiInfo) {
+    info.lapic_address = madt.u32(36) as u64;
+    info.legacy_pics = madt.u32(40) & 1 != 0;
+    let mut offset = 44;
+    while offset + 2 <= madt.len {
+        let kind = madt.u8(offset);
+        let len = madt.u8(offset + 1) as usize;
+        if len < 2 {
+            break;
+        }
+        match kind {
+            0 => push(
+                &mut info.cpus,
+                LocalApic {
+                    processor_id: madt.u8(offset + 2),
+                    apic_id: madt.u8(offset + 3),
+                    enabled: madt.u32(offset + 4) & 1 != 0,
+                },
+                "local APIC",
+            ),
+            1 => push(
+                &mut info.ioapics,
+                IoApicInfo {
+                    id: madt.u8(offset + 2),
+                    address: madt.u32(offset + 4) as u64,
+                    gsi_base: madt.u32(offset + 8),
+                },
+                "IOAPIC",
+            ),
+            2 => {
+                let (active_low, level_triggered) = flags_polarity(madt.u16(offset + 8));
+                push(
+                    &mut info.overrides,
+                    InterruptOverride {
+                        isa_irq: madt.u8(offset + 3),
+                        gsi: madt.u32(offset + 4),
+                        active_low,
+                        level_triggered,
+                    },
+                    "interrupt source override",
+                )
+            }
+            3 => {
+                let (active_low, level_triggered) = flags_polarity(madt.u16(offset + 2));
+                push(
+                    &mut info.nmi_sources,
+                    NmiSource {
+                        gsi: madt.u32(offset + 4),
+                        active_low,
+                        level_triggered,
+                    },
+                    "NMI source",
+                )
+            }
+            4 => {
+                let (active_low, level_triggered) = flags_polarity(madt.u16(offset + 3));
+                push(
+                    &mut info.lapic_nmis,
+                    LocalApicNmi {
+                        processor_id: madt.u8(offset + 2),
+                        lint: madt.u8(offset + 5),
+                        active_low,
+                        level_triggered,
+                    },
+                    "local APIC NMI",
+                )
+            }
+            5 => info.lapic_address = madt.u64(offset + 4),
+            _ => {}
+        }
+        offset += len;
+    }
+}
+
+fn parse_fadt(fadt: Table) -> Fadt {
+    let mut pm_timer_block = fadt.u32(76);
+    // ACPI 2.0+ has a 64-bit X_PM_TMR_BLK generic address; prefer it when it is I/O space
+    if fadt.len >= 220 && fadt.u8(208) == 1 && fadt.u64(212) != 0 {
+        pm_timer_block = fadt.u64(212) as u32;
+    }
+    Fadt {
+        sci_interrupt: fadt.u16(46),
+        pm_timer_block,
+        pm_timer_32bit: fadt.len >= 116 && fadt.u32(112) & (1 << 8) != 0,
+        century: fadt.u8(108),
+        iapc_boot_arch: if fadt.len >= 111 { fadt.u16(109) } else { 0 },
+    }
+}
+
+fn parse_mcfg(mcfg: Table, info: &mut AcpiInfo) {
+    let mut offset = 44;
+    while offset + 16 <= mcfg.len {
+        push(
+            &mut info.pci_segments,
+            PciSegment {
+                base: mcfg.u64(offset),
+                segment: mcfg.u16(offset + 8),
+                start_bus: mcfg.u8(offset + 10),
+                end_bus: mcfg.u8(offset + 11),
+            },
+            "MCFG",
+        );
+        offset += 16;
+    }
+}
+
+// Validates the RSDP and walks the XSDT (or RSDT on ACPI 1.0), collecting everything the
+// kernel needs so the tables themselves can be reclaimed afterwards.
+pub fn init(rsdp: u64) -> Result<&'static AcpiInfo, AcpiError> {
+    let rsdp_v1 = Table {
+        phys: rsdp,
+        len: 20,
+    };
+    if &rsdp_v1.signature() != b"RSD " || rsdp_v1.u32(4) != u32::from_le_bytes(*b"PTR ") {
+        return Err(AcpiError::BadSignature("RSDP"));
+    }
+    if !rsdp_v1.checksum_ok() {
+        return Err(AcpiError::BadChecksum("RSDP"));
+    }
+    let revision = rsdp_v1.u8(15);
+    let (root, entry_size, root_signature) = if revision >= 2 {
+        let rsdp_v2 = Table {
+            phys: rsdp,
+            len: rsdp_v1.u32(20) as usize,
+        };
+        if !rsdp_v2.checksum_ok() {
+            return Err(AcpiError::BadChecksum("RSDP"));
+        }
+        (Table::sdt(rsdp_v1.u64(24))?, 8, b"XSDT")
+    } else {
+        (Table::sdt(rsdp_v1.u32(16) as u64)?, 4, b"RSDT")
+    };
+    if &root.signature() != root_signature {
+        return Err(AcpiError::BadSignature("XSDT/RSDT"));
+    }
+
+    let mut info = AcpiInfo {
+        revision,
+        lapic_address: 0,
+        legacy_pics: false,
+        cpus: [None; MAX_CPUS],
+        ioapics: [None; MAX_IOAPICS],
+        overrides: [None; MAX_OVERRIDES],
+        lapic_nmis: [None; MAX_NMIS],
+        nmi_sources: [None; MAX_NMIS],
+        fadt: None,
+        hpet: None,
+        pci_segments: [None; MAX_PCI_SEGMENTS],
+    };
+    let mut found_madt = false;
+    for i in 0..(root.len - SDT_HEADER_SIZE) / entry_size {
+        let offset = SDT_HEADER_SIZE + i * entry_size;
+        let phys = if entry_size == 8 {
+            root.u64(offset)
+        } else {
+            root.u32(offset) as u64
+        };
+        let table = match Table::sdt(phys) {
+            Ok(table) => table,
+            Err(e) => {
+                println!("[WARN] ACPI: skipping table at {:#x}: {}", phys, e);
+                continue;
+            }
+        };
+        match &table.signature() {
+            b"APIC" => {
+                parse_madt(table, &mut info);
+                found_madt = true;
+            }
+            b"FACP" => info.fadt = Some(parse_fadt(table)),
+            b"HPET" => {
+                info.hpet = Some(Hpet {
+                    address: table.u64(44),
+                    number: table.u8(52),
+                    min_tick: table.u16(53),
+                })
+            }
+            b"MCFG" => parse_mcfg(table, &mut info),
+            _ => {}
+        }
+    }
+    if !found_madt {
+        return Err(AcpiError::MissingTable("MADT"));
+    }
+    Ok(ACPI.call_once(|| info))
+}
+
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI.get()
+}
+
+impl AcpiInfo {
+    pub fn print(&self) {
+        println!(
+            "=== ACPI {} ===",
+            if self.revision >= 2 { "2.0+" } else { "1.0" }
+        );
+        println!(
+            "Local APIC at {:#x}, {} CPUs, legacy PICs {}",
+            self.lapic_address,
+            self.cpus.iter().flatten().filter(|c| c.enabled).count(),
+            self.legacy_pics
+        );
+        for ioapic in self.ioapics.iter().flatten() {
+            println!(
+                "IOAPIC {} at {:#x}, GSI base {}",
+                ioapic.id, ioapic.address, ioapic.gsi_base
+            );
+        }
+        for iso in self.overrides.iter().flatten() {
+            println!(
+                "IRQ {} -> GSI {}{}{}",
+                iso.isa_irq,
+                iso.gsi,
+                if iso.active_low { ", active low" } else { "" },
+                if iso.level_triggered { ", level" } else { "" }
+            );
+        }
+        if let Some(fadt) = self.fadt {
+            println!(
+                "SCI on IRQ {}, PM timer at port {:#x} ({} bit), century register {:#x}, boot flags {:#x}",
+                fadt.sci_interrupt,
+                fadt.pm_timer_block,
+                if fadt.pm_timer_32bit { 32 } else { 24 },
+                fadt.century,
+                fadt.iapc_boot_arch
+            );
+        }
+        if let Some(hpet) = self.hpet {
+            println!(
+                "HPET {} at {:#x}, minimum periodic tick {}",
+                hpet.number, hpet.address, hpet.min_tick
+            );
+        }
+        for segment in self.pci_segments.iter().flatten() {
+            println!(
+                "PCIe segment {} buses {}..={} at {:#x}",
+                segment.segment, segment.start_bus, segment.end_bus, segment.base
+            );
+        }
+    }
+    // Where an ISA IRQ is actually delivered, applying any interrupt source override.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|o| o.isa_irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                isa_irq: irq,
+                gsi: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
@@ -0,0 +1,192 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use super::leak;
+use super::linked_list::HeapStats;
+use super::slab::SlabAllocator;
+use crate::sync::IrqMutex;
+
+pub const DEBUG_ENABLED: bool = cfg!(feature = "heap-debug");
+
+const ALLOC_POISON: u8 = 0xCD;
+const FREE_POISON: u8 = 0xDD;
+const RED_ZONE_BYTE: u8 = 0xFD;
+const MAGIC_ALLOCATED: u64 = 0xA110_CA7E_D0D0_CAFE;
+const MAGIC_FREED: u64 = 0xF4EE_D0D0_DEAD_BEEF;
+// The front red zone starts with room for the free-list links the underlying allocators
+// write into freed blocks, so the header below survives a free and can catch a double free.
+const LINK_SPACE: usize = 16;
+const MIN_RED_ZONE: usize = 32;
+
+// Bytes and allocations as requested by callers, before any slab or red zone overhead
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub allocated: usize,
+    pub peak: usize,
+    pub live: usize,
+    pub total: usize,
+}
+
+#[repr(C)]
+struct DebugHeader {
+    magic: u64,
+    size: usize,
+}
+
+// Wraps the real allocator; with the `heap-debug` feature every allocation is laid out as
+// [front red zone + header][user data][back red zone] and checked again when freed.
+// Allocations are also reported to the leak tracker, which ignores them unless started.
+pub struct DebugAllocator {
+    inner: SlabAllocator,
+    usage: IrqMutex<Usage>,
+}
+impl DebugAllocator {
+    pub const fn new() -> Self {
+        DebugAllocator {
+            inner: SlabAllocator::new(),
+            usage: IrqMutex::new(Usage {
+                allocated: 0,
+                peak: 0,
+                live: 0,
+                total: 0,
+            }),
+        }
+    }
+    pub fn inner(&self) -> &SlabAllocator {
+        &self.inner
+    }
+    pub fn stats(&self) -> HeapStats {
+        let usage = *self.usage.lock();
+        self.inner.fallback().stats(usage)
+    }
+    fn red_zone(layout: &Layout) -> usize {
+        layout.align().max(MIN_RED_ZONE)
+    }
+    fn outer_layout(layout: &Layout) -> Layout {
+        let red_zone = Self::red_zone(layout);
+        Layout::from_size_align(layout.size() + 2 * red_zone, layout.align())
+            .expect("heap debug layout overflow")
+    }
+    fn check_red_zone(start: *const u8, len: usize, ptr: *mut u8, layout: &Layout, which: &str) {
+        for i in 0..len {
+            let byte = unsafe { start.add(i).read() };
+            if byte != RED_ZONE_BYTE {
+                panic!(
+                    "Heap corruption: {} red zone of {:p} overwritten at +{} ({:?})",
+                    which, ptr, i, layout
+                );
+            }
+        }
+    }
+    unsafe fn alloc_checked(&self, layout: Layout) -> *mut u8 {
+        if !DEBUG_ENABLED {
+            return unsafe { self.inner.alloc(layout) };
+        }
+        let red_zone = Self::red_zone(&layout);
+        let outer = unsafe { self.inner.alloc(Self::outer_layout(&layout)) };
+        if outer.is_null() {
+            return ptr::null_mut();
+        }
+        unsafe {
+            let user = outer.add(red_zone);
+            ptr::write_bytes(outer, RED_ZONE_BYTE, red_zone);
+            let header = outer.add(LINK_SPACE) as *mut DebugHeader;
+            header.write(DebugHeader {
+                magic: MAGIC_ALLOCATED,
+                size: layout.size(),
+            });
+            ptr::write_bytes(user, ALLOC_POISON, layout.size());
+            ptr::write_bytes(user.add(layout.size()), RED_ZONE_BYTE, red_zone);
+            user
+        }
+    }
+    unsafe fn dealloc_checked(&self, ptr: *mut u8, layout: Layout) {
+        if !DEBUG_ENABLED {
+            return unsafe { self.inner.dealloc(ptr, layout) };
+        }
+        let red_zone = Self::red_zone(&layout);
+        let outer_layout = Self::outer_layout(&layout);
+        let outer = (ptr as usize).wrapping_sub(red_zone);
+        if !self.inner.contains(outer, outer_layout.size()) {
+            panic!("Free of {:p} outside the heap ({:?})", ptr, layout);
+        }
+        let outer = outer as *mut u8;
+        unsafe {
+            let header = outer.add(LINK_SPACE) as *mut DebugHeader;
+            match (*header).magic {
+                MAGIC_ALLOCATED => {}
+                MAGIC_FREED => panic!("Double free of {:p} ({:?})", ptr, layout),
+                _ => panic!("Free of {:p} which was never allocated ({:?})", ptr, layout),
+            }
+            if (*header).size != layout.size() {
+                panic!(
+                    "Free of {:p} with size {} but it was allocated with {} ({:?})",
+                    ptr,
+                    layout.size(),
+                    (*header).size,
+                    layout
+                );
+            }
+            let header_end = LINK_SPACE + core::mem::size_of::<DebugHeader>();
+            Self::check_red_zone(outer, LINK_SPACE, ptr, &layout, "front");
+            Self::check_red_zone(
+                outer.add(header_end),
+                red_zone - header_end,
+                ptr,
+                &layout,
+                "front",
+            );
+            Self::check_red_zone(ptr.add(layout.size()), red_zone, ptr, &layout, "back");
+            ptr::write_bytes(ptr, FREE_POISON, layout.size());
+            (*header).magic = MAGIC_FREED;
+            self.inner.dealloc(outer, outer_layout);
+        }
+    }
+}
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.alloc_checked(layout) };
+        if !ptr.is_null() {
+            leak::record(ptr as usize, layout.size());
+            let mut usage = self.usage.lock();
+            usage.allocated += layout.size();
+            usage.peak = usage.peak.max(usage.allocated);
+            usage.live += 1;
+            usage.total += 1;
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        leak::forget(ptr as usize);
+        {
+            let mut usage = self.usage.lock();
+            usage.allocated -= layout.size();
+            usage.live -= 1;
+        }
+        unsafe { self.dealloc_checked(ptr, layout) }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = if DEBUG_ENABLED {
+            // The back red zone sits right behind the data, so checked blocks always move
+            let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+            let new_ptr = unsafe { self.alloc_checked(new_layout) };
+            if !new_ptr.is_null() {
+                unsafe {
+                    ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc_checked(ptr, layout);
+                }
+            }
+            new_ptr
+        } else {
+            unsafe { self.inner.realloc(ptr, layout, new_size) }
+        };
+        if !new_ptr.is_null() {
+            leak::forget(ptr as usize);
+            leak::record(new_ptr as usize, new_size);
+            let mut usage = self.usage.lock();
+            usage.allocated = usage.allocated + new_size - layout.size();
+            usage.peak = usage.peak.max(usage.allocated);
+        }
+        new_ptr
+    }
+}
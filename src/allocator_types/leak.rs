@@ -0,0 +1,104 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::backtrace::{self, Symbolized};
+use crate::println;
+use crate::sync::IrqMutex;
+
+const MAX_TRACKED: usize = 256;
+const CALLER_DEPTH: usize = 6;
+// Frames belonging to the tracker itself (caller_frames and record) are not recorded
+const SKIP_FRAMES: usize = 2;
+
+#[derive(Clone, Copy)]
+struct LiveAllocation {
+    addr: usize,
+    size: usize,
+    callers: [usize; CALLER_DEPTH],
+}
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKED: AtomicUsize = AtomicUsize::new(0);
+// Allocations that could not be recorded because the table was full
+static MISSED: AtomicUsize = AtomicUsize::new(0);
+static LIVE: IrqMutex<[Option<LiveAllocation>; MAX_TRACKED]> = IrqMutex::new([None; MAX_TRACKED]);
+
+// Starts recording every allocation made through the global allocator until `stop`.
+pub fn start() {
+    TRACKING.store(true, Ordering::SeqCst);
+}
+// Stops recording new allocations; already recorded ones are still removed when freed.
+pub fn stop() {
+    TRACKING.store(false, Ordering::SeqCst);
+}
+pub fn live_count() -> usize {
+    TRACKED.load(Ordering::SeqCst)
+}
+
+// Return addresses of the current call chain, following the saved frame pointers.
+#[inline(never)]
+fn caller_frames() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut depth = 0;
+    backtrace::walk(backtrace::current_frame(), |ret| {
+        if depth >= SKIP_FRAMES {
+            callers[depth - SKIP_FRAMES] = ret;
+        }
+        depth += 1;
+        depth < SKIP_FRAMES + CALLER_DEPTH
+    });
+    callers
+}
+
+#[inline(never)]
+pub fn record(addr: usize, size: usize) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let callers = caller_frames();
+    let mut live = LIVE.lock();
+    match live.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(LiveAllocation {
+                addr,
+                size,
+                callers,
+            });
+            TRACKED.fetch_add(1, Ordering::SeqCst);
+        }
+        None => {
+            MISSED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+pub fn forget(addr: usize) {
+    if TRACKED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut live = LIVE.lock();
+    if let Some(slot) = live
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|a| a.addr == addr))
+    {
+        *slot = None;
+        TRACKED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Prints every recorded allocation that has not been freed yet. The table lock is only
+// held while copying one entry, since printing may itself allocate.
+pub fn dump() {
+    println!(
+        "=== Live allocations: {} tracked, {} missed ===",
+        live_count(),
+        MISSED.load(Ordering::SeqCst)
+    );
+    for i in 0..MAX_TRACKED {
+        let Some(allocation) = LIVE.lock()[i] else {
+            continue;
+        };
+        println!("{:#x} {:>8} bytes from", allocation.addr, allocation.size);
+        for &caller in allocation.callers.iter().take_while(|&&c| c != 0) {
+            println!("    {}", Symbolized(caller - 1));
+        }
+    }
+}
@@ -1,9 +1,22 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
-use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+use super::debug::Usage;
+use crate::demand;
+use crate::println;
+use crate::sync::IrqMutex;
+use crate::virtualmapper::unmap_range;
+
+// Above the vmalloc region, so nothing of the kernel is left in the lower half
+pub const HEAP_START: usize = 0xFFFF_E000_0000_0000;
 pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+// The whole [HEAP_START, HEAP_START + HEAP_MAX_SIZE) range belongs to the heap, but only
+// the first HEAP_SIZE bytes are mapped up front; growing just extends the heap's
+// demand-paged region and pages are mapped by the fault handler on first touch.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+const HEAP_GROW_STEP: usize = 256 * 1024;
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
@@ -23,8 +36,14 @@ impl ListNode {
     fn end_addr(&self) -> usize {
         self.start_addr() + self.size
     }
+    // A gap in front of the block stays on the free list, so it has to fit a ListNode.
     fn find_region(&self, size: usize, align: usize) -> Option<(usize, usize)> {
-        let alloc_start = align_up(self.start_addr(), align);
+        let mut alloc_start = align_up(self.start_addr(), align);
+        if alloc_start != self.start_addr()
+            && alloc_start - self.start_addr() < mem::size_of::<ListNode>()
+        {
+            alloc_start = align_up(self.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size)?;
         if alloc_end > self.end_addr() {
             None
@@ -37,17 +56,62 @@ impl ListNode {
         size >= mem::size_of::<ListNode>()
     }
 }
+struct HeapBounds {
+    start: usize,
+    end: usize,
+    min_end: usize,
+    max_end: usize,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub bytes_allocated: usize,
+    pub peak_allocated: usize,
+    pub live_allocations: usize,
+    pub total_allocations: usize,
+    pub free_blocks: usize,
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+}
+impl HeapStats {
+    pub fn print(&self) {
+        println!("=== Heap ===");
+        println!(
+            "Size     {:>8} KiB, in use {} KiB (peak {} KiB)",
+            self.heap_size / 1024,
+            self.bytes_allocated / 1024,
+            self.peak_allocated / 1024
+        );
+        println!(
+            "Allocations {} live, {} total",
+            self.live_allocations, self.total_allocations
+        );
+        println!(
+            "Free list {} blocks, {} KiB free, largest block {} KiB",
+            self.free_blocks,
+            self.free_bytes / 1024,
+            self.largest_free_block / 1024
+        );
+    }
+}
 pub struct LinkedListAllocator {
-    head: Mutex<Option<&'static mut ListNode>>,
+    head: IrqMutex<Option<&'static mut ListNode>>,
+    bounds: IrqMutex<HeapBounds>,
 }
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         LinkedListAllocator {
-            head: Mutex::new(None),
+            head: IrqMutex::new(None),
+            bounds: IrqMutex::new(HeapBounds {
+                start: 0,
+                end: 0,
+                min_end: 0,
+                max_end: 0,
+            }),
         }
     }
 
-    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize, max_size: usize) {
         assert_eq!(
             align_up(heap_start, mem::align_of::<ListNode>()),
             heap_start
@@ -63,6 +127,115 @@ impl LinkedListAllocator {
 
             *self.head.lock() = Some(&mut *node_ptr);
         }
+        *self.bounds.lock() = HeapBounds {
+            start: heap_start,
+            end: heap_start + heap_size,
+            min_end: heap_start + heap_size,
+            max_end: heap_start + max_size,
+        };
+        demand::register_region(
+            heap_start as u64,
+            heap_size as u64,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            "kernel heap",
+        );
+    }
+
+    // Extends the heap far enough to fit `size` bytes at `align` and hands the new range to
+    // the free list. Fails once the heap has reached its maximum size.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let mut bounds = self.bounds.lock();
+        let wanted = align_up(size + align, HEAP_GROW_STEP);
+        let grow_by = wanted.min(bounds.max_end - bounds.end);
+        // Aligning inside the new block can cost up to align - 1 bytes
+        if grow_by < size + align - 1 {
+            return false;
+        }
+        let start = bounds.end;
+        bounds.end += grow_by;
+        demand::resize_region(bounds.start as u64, (bounds.end - bounds.start) as u64);
+        drop(bounds);
+        self.dealloc_to_list(start, grow_by);
+        true
+    }
+
+    // Whether [addr, addr + size) lies inside the currently mapped heap.
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        let bounds = self.bounds.lock();
+        addr >= bounds.start && addr.saturating_add(size) <= bounds.end
+    }
+
+    // `usage` comes from the allocator front end, which sees every request, including the
+    // ones served from slabs.
+    pub fn stats(&self, usage: Usage) -> HeapStats {
+        let mut free_blocks = 0;
+        let mut free_bytes = 0;
+        let mut largest_free_block = 0;
+        let list = self.head.lock();
+        let mut current = list.as_deref();
+        while let Some(node) = current {
+            free_blocks += 1;
+            free_bytes += node.size;
+            largest_free_block = largest_free_block.max(node.size);
+            current = node.next.as_deref();
+        }
+        drop(list);
+        let bounds = self.bounds.lock();
+        HeapStats {
+            heap_size: bounds.end - bounds.start,
+            bytes_allocated: usage.allocated,
+            peak_allocated: usage.peak,
+            live_allocations: usage.live,
+            total_allocations: usage.total,
+            free_blocks,
+            free_bytes,
+            largest_free_block,
+        }
+    }
+
+    // Unmaps whole free pages at the end of the heap, never going below the initial size.
+    // Returns the number of bytes given back.
+    pub fn shrink(&self) -> usize {
+        let mut bounds = self.bounds.lock();
+        let mut list = self.head.lock();
+        let mut current = &mut *list;
+        while let Some(node) = current {
+            if node.end_addr() == bounds.end {
+                let new_end = align_up(node.start_addr() + mem::size_of::<ListNode>(), 4096)
+                    .max(bounds.min_end);
+                let new_end = if node.start_addr() == new_end {
+                    *current = node.next.take();
+                    new_end
+                } else if new_end < node.end_addr() {
+                    node.size = new_end - node.start_addr();
+                    new_end
+                } else {
+                    return 0;
+                };
+                let released = bounds.end - new_end;
+                bounds.end = new_end;
+                demand::resize_region(bounds.start as u64, (bounds.end - bounds.start) as u64);
+                unmap_range(
+                    VirtAddr::new(new_end as u64),
+                    (released / 4096) as u64,
+                    true,
+                );
+                return released;
+            }
+            current = &mut current.as_mut().unwrap().next;
+        }
+        0
+    }
+
+    // Rounds every block up so it can hold a ListNode once freed and so the node written
+    // after it stays aligned.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
     }
 
     fn alloc_from_list(&mut self, size: usize, align: usize) -> Option<*mut u8> {
@@ -73,7 +246,7 @@ impl LinkedListAllocator {
             let node = current.take()?;
 
             if let Some((alloc_start, alloc_end)) = node.find_region(size, align) {
-                let next = node.next.take();
+                let mut next = node.next.take();
 
                 if node.can_split(alloc_end) {
                     let remainder_start = alloc_end;
@@ -84,8 +257,15 @@ impl LinkedListAllocator {
                     let remainder_ptr = remainder_start as *mut ListNode;
                     unsafe {
                         remainder_ptr.write(remainder_node);
-                        *current = Some(&mut *remainder_ptr);
+                        next = Some(&mut *remainder_ptr);
                     }
+                }
+
+                if alloc_start > node.start_addr() {
+                    // Keep the alignment gap in front of the block as a smaller free node
+                    node.size = alloc_start - node.start_addr();
+                    node.next = next;
+                    *current = Some(node);
                 } else {
                     *current = next;
                 }
@@ -97,6 +277,46 @@ impl LinkedListAllocator {
             current = &mut current.as_mut().unwrap().next;
         }
     }
+    // Takes `size` bytes off the front of the free node starting exactly at `addr`, so the
+    // block that ends there can grow in place.
+    fn claim_adjacent(&mut self, addr: usize, size: usize) -> bool {
+        let mut list = self.head.lock();
+        let mut current = &mut *list;
+
+        loop {
+            let Some(node) = current.take() else {
+                return false;
+            };
+            if node.start_addr() > addr {
+                *current = Some(node);
+                return false;
+            }
+            if node.start_addr() == addr {
+                match node.size.checked_sub(size) {
+                    Some(0) => {
+                        *current = node.next.take();
+                        return true;
+                    }
+                    Some(remainder) if remainder >= mem::size_of::<ListNode>() => {
+                        let mut moved_node = ListNode::new(remainder);
+                        moved_node.next = node.next.take();
+                        let moved_ptr = (addr + size) as *mut ListNode;
+                        unsafe {
+                            moved_ptr.write(moved_node);
+                            *current = Some(&mut *moved_ptr);
+                        }
+                        return true;
+                    }
+                    _ => {
+                        *current = Some(node);
+                        return false;
+                    }
+                }
+            }
+            *current = Some(node);
+            current = &mut current.as_mut().unwrap().next;
+        }
+    }
     fn dealloc_to_list(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
@@ -161,19 +381,52 @@ impl LinkedListAllocator {
 }
 unsafe impl GlobalAlloc for LinkedListAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size().max(mem::size_of::<ListNode>());
-        let align = layout.align();
+        let (size, align) = Self::size_align(layout);
         let result = unsafe {
             let allocator = self as *const Self as *mut LinkedListAllocator;
-            (*allocator).alloc_from_list(size, align)
+            (*allocator).alloc_from_list(size, align).or_else(|| {
+                if (*allocator).grow(size, align) {
+                    (*allocator).alloc_from_list(size, align)
+                } else {
+                    None
+                }
+            })
         };
         result.unwrap_or(ptr::null_mut())
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size().max(mem::size_of::<ListNode>());
+        let (size, _) = Self::size_align(layout);
         unsafe {
             let allocator = self as *const Self as *mut LinkedListAllocator;
             (*allocator).dealloc_to_list(ptr as usize, size);
         }
     }
+    // Grows into the free node right behind the block or splits the tail off into the
+    // free list; only when neither works is the data moved to a new block. A tail too small
+    // for a ListNode cannot stay with the block, dealloc with the new layout would lose it.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let (old, _) = Self::size_align(layout);
+        let (new, _) = Self::size_align(new_layout);
+        let allocator = self as *const Self as *mut LinkedListAllocator;
+        let in_place = if new > old {
+            unsafe { (*allocator).claim_adjacent(ptr as usize + old, new - old) }
+        } else if old - new >= mem::size_of::<ListNode>() {
+            unsafe { (*allocator).dealloc_to_list(ptr as usize + new, old - new) };
+            true
+        } else {
+            new == old
+        };
+        if in_place {
+            return ptr;
+        }
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
 }
@@ -1,12 +1,26 @@
+pub mod debug;
+pub mod leak;
 pub mod linked_list;
+pub mod slab;
 
-use linked_list::{HEAP_SIZE, HEAP_START, LinkedListAllocator};
+use crate::println;
+use debug::DebugAllocator;
+use linked_list::{HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START, HeapStats};
 
 #[global_allocator]
-static ALLOCATOR: LinkedListAllocator = LinkedListAllocator::new();
+static ALLOCATOR: DebugAllocator = DebugAllocator::new();
 
 pub unsafe fn init() {
     unsafe {
-        ALLOCATOR.init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.inner().init(HEAP_START, HEAP_SIZE, HEAP_MAX_SIZE);
     }
+    if debug::DEBUG_ENABLED {
+        println!("[DEBUG] Heap debugging enabled: poisoning, red zones and free checks");
+    }
+}
+pub fn shrink_heap() -> usize {
+    ALLOCATOR.inner().fallback().shrink()
+}
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
 }
@@ -0,0 +1,110 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use super::linked_list::LinkedListAllocator;
+use crate::sync::IrqMutex;
+
+// Block sizes served from per-class free lists. Anything bigger (or more strictly aligned)
+// goes straight to the linked list allocator.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_SIZE: usize = 4096;
+
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&size| size >= required)
+}
+
+pub struct SlabAllocator {
+    classes: IrqMutex<[Option<&'static mut FreeBlock>; SIZE_CLASSES.len()]>,
+    fallback: LinkedListAllocator,
+}
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            classes: IrqMutex::new([const { None }; SIZE_CLASSES.len()]),
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize, max_size: usize) {
+        unsafe {
+            self.fallback.init(heap_start, heap_size, max_size);
+        }
+    }
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        self.fallback.contains(addr, size)
+    }
+    pub fn fallback(&self) -> &LinkedListAllocator {
+        &self.fallback
+    }
+    // Carves a fresh slab from the linked list into blocks of the given class and returns
+    // one of them, threading the rest onto the class free list.
+    fn refill(&self, class: usize) -> *mut u8 {
+        let block_size = SIZE_CLASSES[class];
+        let slab_layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        let slab = unsafe { self.fallback.alloc(slab_layout) };
+        if slab.is_null() {
+            return ptr::null_mut();
+        }
+        let mut classes = self.classes.lock();
+        for offset in (block_size..SLAB_SIZE).step_by(block_size).rev() {
+            let block = unsafe { slab.add(offset) } as *mut FreeBlock;
+            unsafe {
+                block.write(FreeBlock {
+                    next: classes[class].take(),
+                });
+                classes[class] = Some(&mut *block);
+            }
+        }
+        slab
+    }
+}
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = size_class(&layout) else {
+            return unsafe { self.fallback.alloc(layout) };
+        };
+        let popped = {
+            let mut classes = self.classes.lock();
+            classes[class].take().map(|block| {
+                classes[class] = block.next.take();
+                block as *mut FreeBlock as *mut u8
+            })
+        };
+        popped.unwrap_or_else(|| self.refill(class))
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(class) = size_class(&layout) else {
+            return unsafe { self.fallback.dealloc(ptr, layout) };
+        };
+        let mut classes = self.classes.lock();
+        let block = ptr as *mut FreeBlock;
+        unsafe {
+            block.write(FreeBlock {
+                next: classes[class].take(),
+            });
+            classes[class] = Some(&mut *block);
+        }
+    }
+    // Blocks that stay in the same class are left where they are, and blocks that never
+    // belonged to a class get the linked list's in-place realloc.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        match (size_class(&layout), size_class(&new_layout)) {
+            (None, None) => return unsafe { self.fallback.realloc(ptr, layout, new_size) },
+            (Some(old), Some(new)) if old == new => return ptr,
+            _ => {}
+        }
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
@@ -1,18 +1,22 @@
 use core::arch::asm;
 use pic8259::ChainedPics;
+use x86_64::PhysAddr;
 use x86_64::instructions::port::Port;
 use x86_64::registers::model_specific::Msr;
 
+use crate::acpi;
+use crate::ioapic;
 use crate::println;
+use crate::timer;
+use crate::virtualmapper::phys_to_virt;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-const IOAPIC_BASE: usize = 0xFEC00000;
-const IOREGSEL: usize = 0x00;
-const IOWIN: usize = 0x10;
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub const LAPIC_PHYS_BASE: u64 = 0xFEE00000;
+pub const IOAPIC_PHYS_BASE: u64 = 0xFEC00000;
+pub static PICS: crate::sync::Mutex<ChainedPics> =
+    crate::sync::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 pub static mut APIC_BASE: usize = 0;
-fn cpuid(eax: u32) -> (u32, u32, u32) {
+pub fn cpuid(eax: u32) -> (u32, u32, u32) {
     let (mut eax_out, mut ecx, mut edx): (u32, u32, u32);
     unsafe {
         asm!(
@@ -29,6 +33,28 @@ pub fn has_apic() -> bool {
     (edx & (1 << 9)) != 0
 }
 
+// Fixed delivery, or NMI for LINT pins the MADT says carry NMIs
+fn lint_config(lint: u8) -> Option<u32> {
+    let info = acpi::info()?;
+    let apic_id = lapic_id();
+    let processor_id = info
+        .cpus
+        .iter()
+        .flatten()
+        .find(|cpu| cpu.apic_id == apic_id)
+        .map(|cpu| cpu.processor_id);
+    let nmi = info.lapic_nmis.iter().flatten().find(|nmi| {
+        nmi.lint == lint && (nmi.processor_id == 0xFF || Some(nmi.processor_id) == processor_id)
+    })?;
+    let mut value = 0x400;
+    if nmi.active_low {
+        value |= 1 << 13;
+    }
+    if nmi.level_triggered {
+        value |= 1 << 15;
+    }
+    Some(value)
+}
 fn init_pics() {
     unsafe {
         PICS.lock().initialize();
@@ -45,66 +71,37 @@ fn enable_APIC() {
     unsafe {
         let value = apic_base_msr.read();
         apic_base_msr.write(value | (1 << 11));
-        let apic_base = (value & 0xFFFF_FFFF_F000) as usize;
+        let lapic_phys = acpi::info().map_or(value & 0xFFFF_FFFF_F000, |info| info.lapic_address);
+        let apic_base = phys_to_virt(PhysAddr::new(lapic_phys)).as_u64() as usize;
         APIC_BASE = apic_base;
         write_apic_register(apic_base, 0xF0, 0x1FF);
-        write_apic_register(apic_base, 0x350, 1 << 16);
-        write_apic_register(apic_base, 0x360, 0x400);
+        write_apic_register(apic_base, 0x350, lint_config(0).unwrap_or(1 << 16));
+        write_apic_register(apic_base, 0x360, lint_config(1).unwrap_or(0x400));
         write_apic_register(apic_base, 0x370, 0x33);
         write_apic_register(apic_base, 0x080, 0);
-        timer_config();
-    }
-}
-fn timer_config() {
-    unsafe {
-        write_apic_register(APIC_BASE, 0x3E0, 0xB);
-        write_apic_register(APIC_BASE, 0x320, 0x20020);
-        write_apic_register(APIC_BASE, 0x380, 0x100000);
     }
+    timer::init();
 }
-unsafe fn read_apic_register(apic_base: usize, offset: usize) -> u32 {
-    let apic_base = (apic_base & 0xFFFF_FFFF_F000) as *const u32;
+pub unsafe fn read_apic_register(apic_base: usize, offset: usize) -> u32 {
+    let apic_base = (apic_base & !0xFFF) as *const u32;
     unsafe { apic_base.add(offset / 4).read_volatile() }
 }
 pub unsafe fn write_apic_register(apic_base: usize, offset: usize, value: u32) {
-    let apic_base = (apic_base & 0xFFFF_FFFF_F000) as *mut u32;
+    let apic_base = (apic_base & !0xFFF) as *mut u32;
 
     unsafe {
         apic_base.add(offset / 4).write_volatile(value);
     }
 }
-pub unsafe fn ioapic_read(reg: u8) -> u32 {
-    let regsel = (IOAPIC_BASE + IOREGSEL) as *mut u32;
-    let window = (IOAPIC_BASE + IOWIN) as *const u32;
-    unsafe {
-        regsel.write_volatile(reg as u32);
-        window.read_volatile()
-    }
-}
-
-pub unsafe fn ioapic_write(reg: u8, value: u32) {
-    let regsel = (IOAPIC_BASE + IOREGSEL) as *mut u32;
-    let window = (IOAPIC_BASE + IOWIN) as *mut u32;
-    unsafe {
-        regsel.write_volatile(reg as u32);
-        window.write_volatile(value);
-    }
-}
-pub fn enable_keyboard_inter() {
-    const KEYBOARD_IRQ: u8 = 1;
-    const KEYBOARD_VECTOR: u8 = 33;
-    let low_index = 0x10 + (KEYBOARD_IRQ * 2);
-    let high_index = low_index + 1;
-    let low_bit = KEYBOARD_VECTOR as u32;
-    let high_bit = 0u32;
-    unsafe {
-        ioapic_write(low_index, low_bit);
-        ioapic_write(high_index, high_bit);
-    }
+pub fn lapic_id() -> u8 {
+    unsafe { (read_apic_register(APIC_BASE, 0x020) >> 24) as u8 }
 }
 pub fn init() {
-    init_pics();
-    disable_pics();
+    // Without the PCAT_COMPAT flag there are no 8259s to remap and mask
+    if acpi::info().is_none_or(|info| info.legacy_pics) {
+        init_pics();
+        disable_pics();
+    }
     enable_APIC();
-    enable_keyboard_inter();
+    ioapic::init();
 }
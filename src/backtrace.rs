@@ -0,0 +1,176 @@
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use uefi::boot::{self, AllocateType};
+use uefi::cstr16;
+use uefi::mem::memory_map::MemoryType;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use x86_64::PhysAddr;
+
+use crate::memory::KernelImage;
+use crate::println;
+use crate::virtualmapper::{KERNEL_BASE, phys_to_virt};
+
+const MAX_FRAMES: usize = 32;
+// A saved frame pointer further than this above the current one is treated as garbage
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// Text file written by qemu/symbols.sh: one "<rva in hex> <function>" line per function,
+// sorted by address.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolFile {
+    pub phys: u64,
+    pub len: usize,
+}
+
+struct Symbols {
+    image: KernelImage,
+    file: Option<SymbolFile>,
+}
+
+static SYMBOLS: Once<Symbols> = Once::new();
+// Set once a backtrace has been printed so a panic inside the unwinder cannot recurse
+static TRACED: AtomicBool = AtomicBool::new(false);
+
+// Reads the symbol table from the ESP into loader memory. Must run before boot services
+// are exited; the frames have to be protected once the frame allocator takes over.
+pub fn load_symbol_file() -> Option<SymbolFile> {
+    let mut fs = boot::get_image_file_system(boot::image_handle()).ok()?;
+    let mut root = fs.open_volume().ok()?;
+    let mut file = root
+        .open(
+            cstr16!("\\efi\\boot\\kernel.sym"),
+            FileMode::Read,
+            FileAttribute::empty(),
+        )
+        .ok()?
+        .into_regular_file()?;
+    file.set_position(RegularFile::END_OF_FILE).ok()?;
+    let len = file.get_position().ok()? as usize;
+    file.set_position(0).ok()?;
+    if len == 0 {
+        return None;
+    }
+    let pages = len.div_ceil(4096);
+    let buffer =
+        boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).ok()?;
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr(), len) };
+    match file.read(data) {
+        Ok(read) => Some(SymbolFile {
+            phys: buffer.as_ptr() as u64,
+            len: read,
+        }),
+        Err(_) => {
+            unsafe {
+                let _ = boot::free_pages(buffer, pages);
+            }
+            None
+        }
+    }
+}
+
+pub fn init(image: KernelImage, file: Option<SymbolFile>) {
+    SYMBOLS.call_once(|| Symbols { image, file });
+}
+
+fn symbol_text() -> Option<&'static [u8]> {
+    let file = SYMBOLS.get()?.file?;
+    let start = phys_to_virt(PhysAddr::new(file.phys));
+    Some(unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), file.len) })
+}
+
+// Finds the function containing `addr`, returning its name and the offset into it.
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    let image = SYMBOLS.get()?.image;
+    let addr = addr as u64;
+    // Early frames may still run from the load address rather than KERNEL_BASE
+    let rva = if addr >= KERNEL_BASE && addr - KERNEL_BASE < image.size {
+        addr - KERNEL_BASE
+    } else if addr >= image.base && addr - image.base < image.size {
+        addr - image.base
+    } else {
+        return None;
+    };
+    let mut best = None;
+    for line in symbol_text()?.split(|&b| b == b'\n') {
+        let Some(space) = line.iter().position(|&b| b == b' ') else {
+            continue;
+        };
+        let Some(start) = core::str::from_utf8(&line[..space])
+            .ok()
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        else {
+            continue;
+        };
+        if start > rva {
+            break;
+        }
+        best = Some((start, &line[space + 1..]));
+    }
+    let (start, name) = best?;
+    let name = core::str::from_utf8(name).ok()?;
+    Some((name, (rva - start) as usize))
+}
+
+// Formats an address as "0x... name+0xoff" when it can be resolved. Return addresses
+// should be passed minus one so a call at the very end of a function resolves correctly.
+pub struct Symbolized(pub usize);
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, " {}+{:#x}", name, offset),
+            None => write!(f, " ??"),
+        }
+    }
+}
+
+#[inline(always)]
+pub fn current_frame() -> usize {
+    let rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+    }
+    rbp
+}
+
+// Calls `visit` with the return address of every frame reachable from `rbp`, stopping when
+// it returns false or the chain stops looking like frames on one stack.
+pub fn walk(mut rbp: usize, mut visit: impl FnMut(usize) -> bool) {
+    while rbp != 0 && rbp.is_multiple_of(8) {
+        let (next, ret) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        if ret == 0 || !visit(ret) {
+            break;
+        }
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+// Prints `ip` followed by the call chain starting at frame `rbp`. Only the first call
+// does anything, so a fault while unwinding cannot loop.
+pub fn print_trace(ip: Option<usize>, rbp: usize) {
+    if TRACED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    println!("Backtrace:");
+    if let Some(ip) = ip {
+        println!("  {}", Symbolized(ip));
+    }
+    let mut depth = 0;
+    walk(rbp, |ret| {
+        println!("  {}", Symbolized(ret - 1));
+        depth += 1;
+        depth < MAX_FRAMES
+    });
+    if SYMBOLS.get().and_then(|s| s.file).is_none() {
+        println!("  (no kernel.sym loaded, run qemu/symbols.sh)");
+    }
+}
+#[inline(never)]
+pub fn print_backtrace() {
+    print_trace(None, current_frame());
+}
@@ -0,0 +1,154 @@
+use crate::memory::{ZONE_COUNT, Zone};
+
+pub const MAX_ORDER: usize = 10;
+const NONE: u32 = u32::MAX;
+const NOT_FREE: u8 = u8::MAX;
+
+#[derive(Clone, Copy)]
+pub struct FreeLink {
+    next: u32,
+    prev: u32,
+}
+
+// Free lists are kept outside of the frames they describe: `links` holds the list
+// pointers for every frame that heads a free block and `orders` holds that block's order
+// (or NOT_FREE). Both arrays are indexed by frame number. Each zone has its own set of
+// free lists; zone boundaries are aligned to the largest block so a block never straddles two.
+pub struct BuddyAllocator {
+    links: *mut FreeLink,
+    orders: *mut u8,
+    total_frames: usize,
+    free_heads: [[u32; MAX_ORDER + 1]; ZONE_COUNT],
+    free_blocks: [[usize; MAX_ORDER + 1]; ZONE_COUNT],
+}
+
+impl BuddyAllocator {
+    pub fn metadata_size(total_frames: usize) -> usize {
+        total_frames * (core::mem::size_of::<FreeLink>() + 1)
+    }
+    // `metadata` must point to at least `metadata_size(total_frames)` writable bytes,
+    // aligned for FreeLink. Every frame starts out as not free.
+    pub unsafe fn new(metadata: *mut u8, total_frames: usize) -> Self {
+        let links = metadata as *mut FreeLink;
+        let orders = unsafe { metadata.add(total_frames * core::mem::size_of::<FreeLink>()) };
+        unsafe {
+            core::ptr::write_bytes(orders, NOT_FREE, total_frames);
+        }
+        BuddyAllocator {
+            links,
+            orders,
+            total_frames,
+            free_heads: [[NONE; MAX_ORDER + 1]; ZONE_COUNT],
+            free_blocks: [[0; MAX_ORDER + 1]; ZONE_COUNT],
+        }
+    }
+    pub fn relocate(&mut self, offset: u64) {
+        self.links = (self.links as u64 + offset) as *mut FreeLink;
+        self.orders = (self.orders as u64 + offset) as *mut u8;
+    }
+    fn order_of(&self, frame: usize) -> u8 {
+        unsafe { self.orders.add(frame).read() }
+    }
+    fn set_order(&mut self, frame: usize, order: u8) {
+        unsafe { self.orders.add(frame).write(order) }
+    }
+    fn link(&self, frame: usize) -> FreeLink {
+        unsafe { self.links.add(frame).read() }
+    }
+    fn set_link(&mut self, frame: usize, link: FreeLink) {
+        unsafe { self.links.add(frame).write(link) }
+    }
+    fn push(&mut self, frame: usize, order: usize) {
+        let zone = Zone::of_frame(frame) as usize;
+        let head = self.free_heads[zone][order];
+        self.set_link(
+            frame,
+            FreeLink {
+                next: head,
+                prev: NONE,
+            },
+        );
+        if head != NONE {
+            let mut head_link = self.link(head as usize);
+            head_link.prev = frame as u32;
+            self.set_link(head as usize, head_link);
+        }
+        self.free_heads[zone][order] = frame as u32;
+        self.set_order(frame, order as u8);
+        self.free_blocks[zone][order] += 1;
+    }
+    fn remove(&mut self, frame: usize, order: usize) {
+        let zone = Zone::of_frame(frame) as usize;
+        let link = self.link(frame);
+        if link.prev != NONE {
+            let mut prev = self.link(link.prev as usize);
+            prev.next = link.next;
+            self.set_link(link.prev as usize, prev);
+        } else {
+            self.free_heads[zone][order] = link.next;
+        }
+        if link.next != NONE {
+            let mut next = self.link(link.next as usize);
+            next.prev = link.prev;
+            self.set_link(link.next as usize, next);
+        }
+        self.set_order(frame, NOT_FREE);
+        self.free_blocks[zone][order] -= 1;
+    }
+    // Returns the first frame of a naturally aligned block of 2^order frames taken from
+    // `zone`, falling back to lower zones when it is exhausted.
+    pub fn alloc(&mut self, order: usize, zone: Zone) -> Option<usize> {
+        let (zone, found) = (0..=zone as usize).rev().find_map(|z| {
+            let found = (order..=MAX_ORDER).find(|&o| self.free_heads[z][o] != NONE)?;
+            Some((z, found))
+        })?;
+        let frame = self.free_heads[zone][found] as usize;
+        self.remove(frame, found);
+        for split in (order..found).rev() {
+            self.push(frame + (1 << split), split);
+        }
+        Some(frame)
+    }
+    // Gives back a block previously handed out by `alloc`, merging it with its buddies.
+    pub fn free(&mut self, frame: usize, order: usize) {
+        let mut frame = frame;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy + (1 << order) > self.total_frames || self.order_of(buddy) != order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+    // Pulls a single frame out of whichever free block contains it, returning the rest of
+    // the block to the free lists. Returns false if the frame was not free.
+    pub fn take(&mut self, frame: usize) -> bool {
+        let Some((mut head, mut order)) = (0..=MAX_ORDER)
+            .map(|o| (frame & !((1 << o) - 1), o))
+            .find(|&(head, o)| head < self.total_frames && self.order_of(head) == o as u8)
+        else {
+            return false;
+        };
+        self.remove(head, order);
+        while order > 0 {
+            order -= 1;
+            let upper = head + (1 << order);
+            if frame >= upper {
+                self.push(head, order);
+                head = upper;
+            } else {
+                self.push(upper, order);
+            }
+        }
+        true
+    }
+    pub fn free_frames(&self, zone: Zone) -> usize {
+        (0..=MAX_ORDER)
+            .map(|o| self.free_blocks[zone as usize][o] << o)
+            .sum()
+    }
+}
@@ -1,13 +1,13 @@
 use crate::framebuffer::FrameBuffer;
 use crate::psfparser::psffont;
+use crate::sync::IrqMutex;
 use core::fmt;
-use spin::Mutex;
 
-static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+static CONSOLE: IrqMutex<Option<Console>> = IrqMutex::new(None);
 
 pub struct Console {
     framebuffer: FrameBuffer,
-    font: &'static psffont,
+    font: psffont,
     x: usize,
     y: usize,
     fg_color: u32,
@@ -20,12 +20,7 @@ pub struct Console {
     margin_t: usize,
 }
 impl Console {
-    pub fn new(
-        framebuffer: FrameBuffer,
-        font: &'static psffont,
-        fg_color: u32,
-        bg_color: u32,
-    ) -> Self {
+    pub fn new(framebuffer: FrameBuffer, font: psffont, fg_color: u32, bg_color: u32) -> Self {
         let screen_width = framebuffer.width();
         let screen_height = framebuffer.height();
         let char_width = font.width();
@@ -45,7 +40,7 @@ impl Console {
             margin_t: 10,
         }
     }
-    pub fn init(frambuffer: FrameBuffer, font: &'static psffont) {
+    pub fn init(frambuffer: FrameBuffer, font: psffont) {
         let mut console = Self::new(frambuffer, font, 0x008000, 0x000000);
         console.clear();
         *CONSOLE.lock() = Some(console);
@@ -72,7 +67,7 @@ impl Console {
                     return self.write_char(ch);
                 }
                 self.framebuffer
-                    .draw_char(ch, screen_x, screen_y, self.fg_color, self.font);
+                    .draw_char(ch, screen_x, screen_y, self.fg_color, &self.font);
                 self.x += 1;
             }
         }
@@ -88,7 +83,7 @@ impl Console {
             self.bg_color,
         );
         self.framebuffer
-            .draw_char(ch, screen_x, screen_y, self.fg_color, self.font);
+            .draw_char(ch, screen_x, screen_y, self.fg_color, &self.font);
     }
     fn newline(&mut self) {
         self.y += 1;
@@ -141,6 +136,28 @@ pub fn _print(args: fmt::Arguments) {
         console.write_fmt(args).unwrap();
     }
 }
+// For NMI and machine check handlers, which can interrupt code in the middle of a print.
+// The output is dropped instead of waiting for a lock that may never be released.
+pub fn _try_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(mut console) = CONSOLE.try_lock()
+        && let Some(ref mut console) = *console
+    {
+        console.write_fmt(args).unwrap();
+    }
+}
+pub fn relocate_framebuffer(addr: usize) {
+    if let Some(ref mut console) = *CONSOLE.lock() {
+        console.framebuffer.relocate(addr);
+    }
+}
+// Swaps in the same font parsed again after the kernel moved, so the glyph data is no
+// longer read through the image's load address.
+pub fn relocate_font(font: psffont) {
+    if let Some(ref mut console) = *CONSOLE.lock() {
+        console.font = font;
+    }
+}
 pub fn backspace() {
     let mut console = CONSOLE.lock();
     if let Some(ref mut console) = *console {
@@ -166,3 +183,8 @@ macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
+
+#[macro_export]
+macro_rules! try_println {
+    ($($arg:tt)*) => ($crate::console::_try_print(format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,112 @@
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+
+use crate::memory::{self, BitmapFrameAllocator};
+use crate::sync::IrqMutex;
+use crate::virtualmapper::{active_mapper, phys_to_virt};
+use crate::vmalloc::VmError;
+
+const MAX_REGIONS: usize = 16;
+
+// A virtual range whose pages are only backed by zeroed frames once they are touched.
+#[derive(Debug, Clone, Copy)]
+pub struct DemandRegion {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+    pub name: &'static str,
+}
+
+#[derive(Debug)]
+pub enum FaultError {
+    NoRegion,
+    Protection(DemandRegion),
+    AccessDenied(DemandRegion),
+    AllocatorBusy(DemandRegion),
+    MapFailed(DemandRegion, VmError),
+}
+
+static REGIONS: IrqMutex<[Option<DemandRegion>; MAX_REGIONS]> = IrqMutex::new([None; MAX_REGIONS]);
+
+pub fn register_region(start: u64, size: u64, flags: PageTableFlags, name: &'static str) {
+    let mut regions = REGIONS.lock();
+    match regions.iter_mut().find(|r| r.is_none()) {
+        Some(slot) => {
+            *slot = Some(DemandRegion {
+                start,
+                end: start + size,
+                flags,
+                name,
+            })
+        }
+        None => panic!("Too many demand-paged regions, cannot register {}", name),
+    }
+}
+// Moves the end of the region starting at `start`; used as the heap grows and shrinks.
+pub fn resize_region(start: u64, size: u64) {
+    let mut regions = REGIONS.lock();
+    match regions.iter_mut().flatten().find(|r| r.start == start) {
+        Some(region) => region.end = start + size,
+        None => panic!("No demand-paged region at {:#x}", start),
+    }
+}
+// Called from the page fault handler, so it must not spin on a lock the faulting code holds.
+pub fn find_region(addr: u64) -> Option<DemandRegion> {
+    let regions = REGIONS.try_lock()?;
+    regions
+        .iter()
+        .flatten()
+        .find(|r| addr >= r.start && addr < r.end)
+        .copied()
+}
+
+// Maps a fresh zeroed page under `addr` if it belongs to a demand-paged region and the
+// access is one the region allows.
+pub fn resolve_fault(addr: u64, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    let region = find_region(addr).ok_or(FaultError::NoRegion)?;
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::Protection(region));
+    }
+    let write_denied = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE);
+    let fetch_denied = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE);
+    if write_denied || fetch_denied {
+        return Err(FaultError::AccessDenied(region));
+    }
+    let page = Page::containing_address(VirtAddr::new(addr));
+    // The fault may hit while the frame allocator is held, for instance in the middle of a
+    // map_to, so the whole mapping is done under one try_lock instead of waiting for it
+    memory::try_with_frame_allocator(|allocator| map_zeroed(page, region.flags, allocator))
+        .ok_or(FaultError::AllocatorBusy(region))?
+        .map_err(|e| FaultError::MapFailed(region, e))
+}
+
+fn map_zeroed(
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+    allocator: &mut BitmapFrameAllocator,
+) -> Result<(), VmError> {
+    let frame = allocator.allocate_frame().ok_or(VmError::OutOfMemory)?;
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            4096,
+        );
+    }
+    let mut mem_map = unsafe { active_mapper() };
+    match unsafe { mem_map.map_to(page, frame, flags | PageTableFlags::PRESENT, allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(e) => {
+            unsafe { allocator.deallocate_frame(frame) };
+            Err(VmError::MapFailed(e))
+        }
+    }
+}
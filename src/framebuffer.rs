@@ -30,6 +30,10 @@ impl FrameBuffer {
             size_per_pixel: pix_size,
         }
     }
+    pub fn relocate(&mut self, addr: usize) {
+        let size = self.buffer.len();
+        self.buffer = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, size) };
+    }
     pub fn clear_screen(&mut self, color: u32) {
         for y in 0..self.height {
             for x in 0..self.width {
@@ -1,22 +1,23 @@
 use lazy_static::lazy_static;
-use x86_64::VirtAddr;
 use x86_64::instructions::segmentation::{CS, Segment};
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{DS, ES, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+
+use crate::guard;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+const IST_STACK_PAGES: u64 = 5;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            guard::alloc_stack("double fault", IST_STACK_PAGES).top;
+        // Page faults get their own stack so a kernel stack overflow can still be reported
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            guard::alloc_stack("page fault", IST_STACK_PAGES).top;
         tss
     };
 }
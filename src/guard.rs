@@ -0,0 +1,96 @@
+use core::arch::asm;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::demand;
+use crate::sync::IrqMutex;
+use crate::vmalloc;
+
+const MAX_GUARDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardKind {
+    Stack,
+    Heap,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct GuardRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: GuardKind,
+    pub name: &'static str,
+}
+
+static GUARDS: IrqMutex<[Option<GuardRegion>; MAX_GUARDS]> = IrqMutex::new([None; MAX_GUARDS]);
+
+pub fn register_guard(start: u64, size: u64, kind: GuardKind, name: &'static str) {
+    let mut guards = GUARDS.lock();
+    match guards.iter_mut().find(|g| g.is_none()) {
+        Some(slot) => {
+            *slot = Some(GuardRegion {
+                start,
+                end: start + size,
+                kind,
+                name,
+            })
+        }
+        None => panic!("Too many guard regions, cannot register {}", name),
+    }
+}
+// Called from fault handlers, so it must not spin on a lock the faulting code may hold.
+pub fn find_guard(addr: u64) -> Option<GuardRegion> {
+    let guards = GUARDS.try_lock()?;
+    guards
+        .iter()
+        .flatten()
+        .find(|g| addr >= g.start && addr < g.end)
+        .copied()
+}
+
+pub struct KernelStack {
+    pub top: VirtAddr,
+}
+
+// Allocates a kernel stack with an unmapped guard page directly beneath it.
+pub fn alloc_stack(name: &'static str, pages: u64) -> KernelStack {
+    new_stack(name, pages, pages)
+}
+// Like alloc_stack, but only the topmost page is mapped up front; the rest is faulted in
+// as the stack grows. Not usable for IST stacks, which the fault handler itself runs on.
+pub fn alloc_demand_stack(name: &'static str, pages: u64) -> KernelStack {
+    new_stack(name, pages, 1)
+}
+fn new_stack(name: &'static str, pages: u64, mapped_pages: u64) -> KernelStack {
+    let guard = vmalloc::reserve((pages + 1) * 4096).expect("No virtual space for kernel stack");
+    let bottom = guard + 4096u64;
+    let top = bottom + pages * 4096;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if let Err(e) = vmalloc::map_fresh(top - mapped_pages * 4096, mapped_pages, flags) {
+        panic!("Failed to map kernel stack {}: {:?}", name, e);
+    }
+    if mapped_pages < pages {
+        demand::register_region(bottom.as_u64(), (pages - mapped_pages) * 4096, flags, name);
+    }
+    register_guard(guard.as_u64(), 4096, GuardKind::Stack, name);
+    KernelStack { top }
+}
+
+// Guards the pages right below and right above the heap.
+pub fn protect_heap(heap_start: u64, heap_size: u64) {
+    register_guard(heap_start - 4096, 4096, GuardKind::Heap, "kernel heap");
+    register_guard(heap_start + heap_size, 4096, GuardKind::Heap, "kernel heap");
+}
+
+// Moves execution onto `stack` and calls `entry`; the old stack is never returned to.
+pub unsafe fn switch_to(stack: &KernelStack, entry: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "xor rbp, rbp",
+            "call {entry}",
+            top = in(reg) stack.top.as_u64(),
+            entry = in(reg) entry,
+            options(noreturn)
+        );
+    }
+}
@@ -1,77 +1,456 @@
 use crate::apic::APIC_BASE;
 use crate::apic::write_apic_register;
+use crate::backtrace;
+use crate::demand::{self, FaultError};
 use crate::gdt;
+use crate::guard::{GuardKind, GuardRegion, find_guard};
 use crate::hlt_loop;
-use crate::keyboard::handle_scancode;
-use crate::{print, println};
+use crate::irq;
+use crate::sync;
+use crate::time;
+use crate::{print, println, try_println};
 use lazy_static::lazy_static;
-use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::PageFaultErrorCode;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::mxcsr;
+use x86_64::structures::idt::{
+    ExceptionVector, InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue,
+};
+use x86_64::structures::idt::{PageFaultErrorCode, SelectorErrorCode};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.breakpoint.set_handler_fn(bkpoint_handler);
-        idt.divide_error.set_handler_fn(divbyzero);
+        idt.machine_check.set_handler_fn(machine_check_handler);
         unsafe {
+            idt.divide_error
+                .set_handler_addr(stub::<{ ExceptionVector::Division as u8 }>());
+            idt.overflow
+                .set_handler_addr(stub::<{ ExceptionVector::Overflow as u8 }>());
+            idt.bound_range_exceeded
+                .set_handler_addr(stub::<{ ExceptionVector::BoundRange as u8 }>());
+            idt.invalid_opcode
+                .set_handler_addr(stub::<{ ExceptionVector::InvalidOpcode as u8 }>());
+            idt.device_not_available
+                .set_handler_addr(stub::<{ ExceptionVector::DeviceNotAvailable as u8 }>());
             idt.double_fault
-                .set_handler_fn(double_fault)
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::Double as u8 }>())
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::InvalidTss as u8 }>());
+            idt.segment_not_present
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::SegmentNotPresent as u8 }>());
+            idt.stack_segment_fault
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::Stack as u8 }>());
+            idt.general_protection_fault
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::GeneralProtection as u8 }>());
+            idt.page_fault
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::Page as u8 }>())
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.x87_floating_point
+                .set_handler_addr(stub::<{ ExceptionVector::X87FloatingPoint as u8 }>());
+            idt.alignment_check
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::AlignmentCheck as u8 }>());
+            idt.simd_floating_point
+                .set_handler_addr(stub::<{ ExceptionVector::SimdFloatingPoint as u8 }>());
+            idt.virtualization
+                .set_handler_addr(stub::<{ ExceptionVector::Virtualization as u8 }>());
+            idt.cp_protection_exception
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::ControlProtection as u8 }>());
+            idt.hv_injection_exception
+                .set_handler_addr(stub::<{ ExceptionVector::HypervisorInjection as u8 }>());
+            idt.vmm_communication_exception
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::VmmCommunication as u8 }>());
+            idt.security_exception
+                .set_handler_addr(stub_with_code::<{ ExceptionVector::Security as u8 }>());
         }
 
         idt[0xFF].set_handler_fn(spurious_interrupt_handler);
         idt[0x33].set_handler_fn(levt_error_handler);
         idt[32].set_handler_fn(timer_interup_handler);
-        idt[33].set_handler_fn(keyboard_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        irq::install_stubs(&mut idt);
         idt
     };
 }
 pub fn init_idt() {
     IDT.load();
 }
+
+type ExceptionHandler = fn(&ExceptionFrame);
+
+// Name and handler of every architectural exception, indexed by vector. Vectors with an
+// x86-interrupt handler of their own never reach exception_dispatch. A handler that
+// returns resumes the interrupted code.
+static EXCEPTIONS: [(&str, ExceptionHandler); 32] = [
+    ("Divide Error", fatal),
+    ("Debug", fatal),
+    ("Non-Maskable Interrupt", fatal),
+    ("Breakpoint", fatal),
+    ("Overflow", fatal),
+    ("Bound Range Exceeded", fatal),
+    ("Invalid Opcode", invalid_opcode),
+    ("Device Not Available", fatal),
+    ("Double Fault", double_fault),
+    ("Coprocessor Segment Overrun", fatal),
+    ("Invalid TSS", selector_fault),
+    ("Segment Not Present", selector_fault),
+    ("Stack Segment Fault", selector_fault),
+    ("General Protection Fault", selector_fault),
+    ("Page Fault", page_fault),
+    ("Reserved", fatal),
+    ("x87 Floating Point", fatal),
+    ("Alignment Check", fatal),
+    ("Machine Check", fatal),
+    ("SIMD Floating Point", simd_floating_point),
+    ("Virtualization", fatal),
+    ("Control Protection", fatal_with_code),
+    ("Reserved", fatal),
+    ("Reserved", fatal),
+    ("Reserved", fatal),
+    ("Reserved", fatal),
+    ("Reserved", fatal),
+    ("Reserved", fatal),
+    ("Hypervisor Injection", fatal),
+    ("VMM Communication", fatal_with_code),
+    ("Security", fatal_with_code),
+    ("Reserved", fatal),
+];
+
+// What exception_common leaves on the stack: the general purpose registers it saved, the
+// vector and error code pushed by the stub, then the frame pushed by the CPU.
+#[repr(C)]
+struct ExceptionFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    error_code: u64,
+    stack_frame: InterruptStackFrameValue,
+}
+
+fn stub<const VECTOR: u8>() -> VirtAddr {
+    VirtAddr::new(exception_stub::<VECTOR> as *const () as u64)
+}
+fn stub_with_code<const VECTOR: u8>() -> VirtAddr {
+    VirtAddr::new(exception_stub_with_code::<VECTOR> as *const () as u64)
+}
+// Pushes a zero in place of the missing error code, so every exception reaches
+// exception_common with the same frame layout.
+#[unsafe(naked)]
+extern "C" fn exception_stub<const VECTOR: u8>() {
+    core::arch::naked_asm!(
+        "push 0",
+        "push {vector}",
+        "jmp {common}",
+        vector = const VECTOR,
+        common = sym exception_common,
+    );
+}
+#[unsafe(naked)]
+extern "C" fn exception_stub_with_code<const VECTOR: u8>() {
+    core::arch::naked_asm!(
+        "push {vector}",
+        "jmp {common}",
+        vector = const VECTOR,
+        common = sym exception_common,
+    );
+}
+// The CPU aligns the stack to 16 bytes before pushing its frame, and the 16 quadwords
+// pushed from there on keep it aligned for the call.
+#[unsafe(naked)]
+extern "C" fn exception_common() {
+    core::arch::naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16",
+        "iretq",
+        dispatch = sym exception_dispatch,
+    );
+}
+// sysv64 so the frame arrives in RDI whatever the target's default convention is
+extern "sysv64" fn exception_dispatch(frame: &ExceptionFrame) {
+    let (_, handler) = EXCEPTIONS[frame.vector as usize];
+    handler(frame);
+}
+
+// Prints everything the CPU hands us about an exception plus the control registers.
+fn dump_exception(vector: u8, stackframe: &InterruptStackFrameValue) {
+    println!();
+    println!(
+        "EXCEPTION {} ({:#04x}) {}",
+        vector, vector, EXCEPTIONS[vector as usize].0
+    );
+    println!(
+        "RIP {:#018x}  CS  {:#06x}  RFLAGS {:#018x}",
+        stackframe.instruction_pointer.as_u64(),
+        stackframe.code_segment.0,
+        stackframe.cpu_flags.bits()
+    );
+    println!(
+        "RSP {:#018x}  SS  {:#06x}",
+        stackframe.stack_pointer.as_u64(),
+        stackframe.stack_segment.0
+    );
+    let (cr3_frame, _) = Cr3::read_raw();
+    println!(
+        "CR0 {:#018x}  CR2 {:#018x}  CR3 {:#018x}  CR4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        cr3_frame.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+// The general purpose registers of the interrupted code, as saved by exception_common.
+fn dump_registers(frame: &ExceptionFrame) {
+    println!(
+        "RAX {:#018x}  RBX {:#018x}  RCX {:#018x}",
+        frame.rax, frame.rbx, frame.rcx
+    );
+    println!(
+        "RDX {:#018x}  RSI {:#018x}  RDI {:#018x}",
+        frame.rdx, frame.rsi, frame.rdi
+    );
+    println!(
+        "RBP {:#018x}  R8  {:#018x}  R9  {:#018x}",
+        frame.rbp, frame.r8, frame.r9
+    );
+    println!(
+        "R10 {:#018x}  R11 {:#018x}  R12 {:#018x}",
+        frame.r10, frame.r11, frame.r12
+    );
+    println!(
+        "R13 {:#018x}  R14 {:#018x}  R15 {:#018x}",
+        frame.r13, frame.r14, frame.r15
+    );
+}
+// Error codes of #TS, #NP, #SS and #GP name the segment selector that caused the fault.
+fn describe_selector(error_code: u64) {
+    if error_code == 0 {
+        println!("Error code 0 (not caused by a selector)");
+        return;
+    }
+    let selector = SelectorErrorCode::new_truncate(error_code);
+    println!(
+        "Error code {:#x}: {:?} index {}{}",
+        error_code,
+        selector.descriptor_table(),
+        selector.index(),
+        if selector.external() {
+            " (external event)"
+        } else {
+            ""
+        }
+    );
+}
+fn dump_frame(frame: &ExceptionFrame) {
+    dump_exception(frame.vector as u8, &frame.stack_frame);
+    dump_registers(frame);
+}
+fn fatal_exception(frame: &ExceptionFrame) -> ! {
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    backtrace::print_trace(Some(rip as usize), frame.rbp as usize);
+    panic!(
+        "unhandled {} ({:#04x}) at rip {:#x}",
+        EXCEPTIONS[frame.vector as usize].0, frame.vector, rip
+    );
+}
+fn fatal(frame: &ExceptionFrame) {
+    dump_frame(frame);
+    fatal_exception(frame);
+}
+fn fatal_with_code(frame: &ExceptionFrame) {
+    dump_frame(frame);
+    println!("Error code {:#x}", frame.error_code);
+    fatal_exception(frame);
+}
+fn selector_fault(frame: &ExceptionFrame) {
+    dump_frame(frame);
+    describe_selector(frame.error_code);
+    fatal_exception(frame);
+}
+fn invalid_opcode(frame: &ExceptionFrame) {
+    dump_frame(frame);
+    let rip = frame.stack_frame.instruction_pointer;
+    // The page holding RIP was fetched from, the next one may not be mapped
+    let len = 4.min(4096 - u64::from(rip.page_offset()) as usize);
+    let bytes = unsafe { core::slice::from_raw_parts(rip.as_ptr::<u8>(), len) };
+    println!("Instruction bytes {:02x?}", bytes);
+    fatal_exception(frame);
+}
+fn simd_floating_point(frame: &ExceptionFrame) {
+    dump_frame(frame);
+    println!("MXCSR {:?}", mxcsr::read());
+    fatal_exception(frame);
+}
+fn report_guard_hit(guard: GuardRegion, ip: u64) -> ! {
+    match guard.kind {
+        GuardKind::Stack => panic!("stack overflow in {} (rip {:#x})", guard.name, ip),
+        GuardKind::Heap => panic!(
+            "access to guard page at {:#x} next to the {} (rip {:#x})",
+            guard.start, guard.name, ip
+        ),
+    }
+}
+fn double_fault(frame: &ExceptionFrame) {
+    if let Some(guard) = Cr2::read().ok().and_then(|addr| find_guard(addr.as_u64())) {
+        report_guard_hit(guard, frame.stack_frame.instruction_pointer.as_u64());
+    }
+    fatal(frame);
+}
+extern "x86-interrupt" fn debug_handler(stackframe: InterruptStackFrame) {
+    let dr6: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr6", out(reg) dr6);
+    }
+    println!(
+        "Debug exception at {:#x}, DR6 {:#x}",
+        stackframe.instruction_pointer.as_u64(),
+        dr6
+    );
+}
+// NMIs arrive even while the console lock is held, so nothing here may wait for it.
+extern "x86-interrupt" fn nmi_handler(stackframe: InterruptStackFrame) {
+    try_println!(
+        "Non-maskable interrupt at {:#x}",
+        stackframe.instruction_pointer.as_u64()
+    );
+}
 extern "x86-interrupt" fn bkpoint_handler(stackframe: InterruptStackFrame) {
     println!("Invoked Breakpoint {:#?}", stackframe);
 }
-extern "x86-interrupt" fn divbyzero(stackframe: InterruptStackFrame) {
-    println!("You fucked up{:#?}", stackframe);
-}
-extern "x86-interrupt" fn double_fault(stackframe: InterruptStackFrame, eror_code: u64) -> ! {
-    panic!("You fucked up and caused a doubled fault{:#?}", stackframe);
+// Like an NMI, a machine check can interrupt a print, so it reports what it can without
+// the console lock and stops the CPU instead of panicking.
+extern "x86-interrupt" fn machine_check_handler(stackframe: InterruptStackFrame) -> ! {
+    let vector = ExceptionVector::MachineCheck as u8;
+    try_println!(
+        "EXCEPTION {} ({:#04x}) {} at rip {:#x}, RSP {:#x}",
+        vector,
+        vector,
+        EXCEPTIONS[vector as usize].0,
+        stackframe.instruction_pointer.as_u64(),
+        stackframe.stack_pointer.as_u64()
+    );
+    hlt_loop();
 }
 extern "x86-interrupt" fn spurious_interrupt_handler(stack: InterruptStackFrame) {
+    let _irq = sync::enter_interrupt();
     println!("Triggered spurious_interrupt_handler{:#?}", stack);
 }
 extern "x86-interrupt" fn levt_error_handler(stackframe: InterruptStackFrame) {
+    let _irq = sync::enter_interrupt();
     println!("Triggered levt_error_handler{:#?}", stackframe);
     unsafe {
         write_apic_register(APIC_BASE, 0x0B0, 0);
     }
 }
 extern "x86-interrupt" fn timer_interup_handler(stackframe: InterruptStackFrame) {
-    //print!("Tick");
+    let _irq = sync::enter_interrupt();
+    time::tick();
     unsafe {
         write_apic_register(APIC_BASE, 0x0B0, 0);
     }
 }
-extern "x86-interrupt" fn keyboard_handler(stackframe: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut data_port = Port::<u8>::new(0x60);
-        let scancode = data_port.read();
-        handle_scancode(scancode);
-        // Send EOI to APIC
-        write_apic_register(APIC_BASE, 0x0B0, 0);
+fn page_fault(frame: &ExceptionFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let fault_addr = Cr2::read_raw();
+    if let Some(guard) = find_guard(fault_addr) {
+        report_guard_hit(guard, frame.stack_frame.instruction_pointer.as_u64());
+    }
+    let error = match demand::resolve_fault(fault_addr, error_code) {
+        Ok(()) => return,
+        Err(error) => error,
+    };
+    dump_frame(frame);
+    println!(
+        "{} {} access to {:#x} in {} mode{}",
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "Protection violation on"
+        } else {
+            "Non-present page on"
+        },
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        },
+        fault_addr,
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        },
+        if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            ", reserved bit set in a page table"
+        } else {
+            ""
+        }
+    );
+    match error {
+        FaultError::NoRegion => println!("Address is not in any mapped or demand-paged region"),
+        FaultError::Protection(region) => println!(
+            "Page in {} ({:#x}..{:#x}) is mapped but the access is not permitted",
+            region.name, region.start, region.end
+        ),
+        FaultError::AccessDenied(region) => println!(
+            "{} ({:#x}..{:#x}) does not allow this access, flags {:?}",
+            region.name, region.start, region.end, region.flags
+        ),
+        FaultError::AllocatorBusy(region) => println!(
+            "Could not back {} ({:#x}..{:#x}): the frame allocator is held by the faulting code",
+            region.name, region.start, region.end
+        ),
+        FaultError::MapFailed(region, e) => println!(
+            "Could not back {} ({:#x}..{:#x}): {}",
+            region.name, region.start, region.end, e
+        ),
     }
-}
-extern "x86-interrupt" fn page_fault_handler(
-    stackframe: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    let fault_addr = Cr2::read();
-    println!("Page Fault at {:#x}", fault_addr.unwrap().as_u64());
     println!("Error code {:?}", error_code);
-    println!("Stact Frame {:#?}", stackframe);
-    hlt_loop();
+    fatal_exception(frame);
 }
@@ -0,0 +1,213 @@
+use x86_64::PhysAddr;
+
+use crate::acpi;
+use crate::apic::{self, IOAPIC_PHYS_BASE};
+use crate::println;
+use crate::sync::IrqMutex;
+use crate::vmalloc;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+const MAX_IOAPICS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+impl DeliveryMode {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b111 {
+            0b001 => DeliveryMode::LowestPriority,
+            0b010 => DeliveryMode::Smi,
+            0b100 => DeliveryMode::Nmi,
+            0b101 => DeliveryMode::Init,
+            0b111 => DeliveryMode::ExtInt,
+            _ => DeliveryMode::Fixed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub delivery_mode: DeliveryMode,
+    pub logical_destination: bool,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    pub destination: u8,
+}
+impl RedirectionEntry {
+    // Edge triggered, active high, fixed delivery to the given physical LAPIC ID
+    pub fn new(vector: u8, destination: u8) -> Self {
+        RedirectionEntry {
+            vector,
+            delivery_mode: DeliveryMode::Fixed,
+            logical_destination: false,
+            active_low: false,
+            level_triggered: false,
+            masked: false,
+            destination,
+        }
+    }
+    fn from_raw(raw: u64) -> Self {
+        RedirectionEntry {
+            vector: raw as u8,
+            delivery_mode: DeliveryMode::from_bits(raw >> 8),
+            logical_destination: raw & (1 << 11) != 0,
+            active_low: raw & (1 << 13) != 0,
+            level_triggered: raw & (1 << 15) != 0,
+            masked: raw & (1 << 16) != 0,
+            destination: (raw >> 56) as u8,
+        }
+    }
+    fn to_raw(self) -> u64 {
+        (self.vector as u64)
+            | ((self.delivery_mode as u64) << 8)
+            | ((self.logical_destination as u64) << 11)
+            | ((self.active_low as u64) << 13)
+            | ((self.level_triggered as u64) << 15)
+            | ((self.masked as u64) << 16)
+            | ((self.destination as u64) << 56)
+    }
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub gsi_base: u32,
+    pub entries: u32,
+    base: usize,
+}
+impl IoApic {
+    // Maps the IOAPIC's registers and masks every redirection entry.
+    pub fn new(phys: u64, gsi_base: u32) -> Self {
+        let base = match vmalloc::ioremap(PhysAddr::new(phys), 0x20) {
+            Ok(base) => base.as_u64() as usize,
+            Err(e) => panic!("Failed to map IOAPIC registers: {}", e),
+        };
+        let mut ioapic = IoApic {
+            id: 0,
+            gsi_base,
+            entries: 0,
+            base,
+        };
+        ioapic.id = ((ioapic.read(IOAPICID) >> 24) & 0xF) as u8;
+        ioapic.entries = ((ioapic.read(IOAPICVER) >> 16) & 0xFF) + 1;
+        for index in 0..ioapic.entries {
+            ioapic.mask(index);
+        }
+        ioapic
+    }
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+    pub fn version(&self) -> u8 {
+        self.read(IOAPICVER) as u8
+    }
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+    pub fn entry(&self, index: u32) -> RedirectionEntry {
+        assert!(index < self.entries, "IOAPIC entry {} out of range", index);
+        let low = self.read(IOREDTBL + index * 2) as u64;
+        let high = self.read(IOREDTBL + index * 2 + 1) as u64;
+        RedirectionEntry::from_raw(low | (high << 32))
+    }
+    pub fn set_entry(&mut self, index: u32, entry: RedirectionEntry) {
+        assert!(index < self.entries, "IOAPIC entry {} out of range", index);
+        let raw = entry.to_raw();
+        // Masked while the halves disagree, then the real low half goes in last
+        self.write(IOREDTBL + index * 2, (raw as u32) | (1 << 16));
+        self.write(IOREDTBL + index * 2 + 1, (raw >> 32) as u32);
+        self.write(IOREDTBL + index * 2, raw as u32);
+    }
+    pub fn mask(&mut self, index: u32) {
+        assert!(index < self.entries, "IOAPIC entry {} out of range", index);
+        let low = self.read(IOREDTBL + index * 2);
+        self.write(IOREDTBL + index * 2, low | (1 << 16));
+    }
+    pub fn unmask(&mut self, index: u32) {
+        assert!(index < self.entries, "IOAPIC entry {} out of range", index);
+        let low = self.read(IOREDTBL + index * 2);
+        self.write(IOREDTBL + index * 2, low & !(1 << 16));
+    }
+}
+
+static IOAPICS: IrqMutex<[Option<IoApic>; MAX_IOAPICS]> =
+    IrqMutex::new([const { None }; MAX_IOAPICS]);
+
+// Registers an IOAPIC reported by firmware.
+pub fn add_ioapic(phys: u64, gsi_base: u32) {
+    let ioapic = IoApic::new(phys, gsi_base);
+    println!(
+        "[OK] IOAPIC {} (version {:#x}) at {:#x}, GSIs {}..{}",
+        ioapic.id,
+        ioapic.version(),
+        phys,
+        gsi_base,
+        gsi_base + ioapic.entries
+    );
+    let mut ioapics = IOAPICS.lock();
+    match ioapics.iter_mut().find(|i| i.is_none()) {
+        Some(slot) => *slot = Some(ioapic),
+        None => panic!("Too many IOAPICs, cannot add the one at {:#x}", phys),
+    }
+}
+// Brings up the IOAPICs listed in the MADT, falling back to the standard address when
+// firmware did not report any, and routes the MADT's NMI sources.
+pub fn init() {
+    let Some(info) = acpi::info().filter(|info| info.ioapics.iter().any(|i| i.is_some())) else {
+        add_ioapic(IOAPIC_PHYS_BASE, 0);
+        return;
+    };
+    for ioapic in info.ioapics.iter().flatten() {
+        add_ioapic(ioapic.address, ioapic.gsi_base);
+    }
+    for nmi in info.nmi_sources.iter().flatten() {
+        let mut entry = RedirectionEntry::new(0, apic::lapic_id());
+        entry.delivery_mode = DeliveryMode::Nmi;
+        entry.active_low = nmi.active_low;
+        entry.level_triggered = nmi.level_triggered;
+        if !set_gsi(nmi.gsi, entry) {
+            println!("[WARN] No IOAPIC serves NMI source GSI {}", nmi.gsi);
+        }
+    }
+}
+
+// Runs `f` on the IOAPIC serving `gsi` together with the entry index, or returns None
+// when no IOAPIC handles that GSI.
+pub fn with_gsi<R>(gsi: u32, f: impl FnOnce(&mut IoApic, u32) -> R) -> Option<R> {
+    let mut ioapics = IOAPICS.lock();
+    let ioapic = ioapics.iter_mut().flatten().find(|i| i.handles(gsi))?;
+    let index = gsi - ioapic.gsi_base;
+    Some(f(ioapic, index))
+}
+pub fn set_gsi(gsi: u32, entry: RedirectionEntry) -> bool {
+    with_gsi(gsi, |ioapic, index| ioapic.set_entry(index, entry)).is_some()
+}
+pub fn gsi_entry(gsi: u32) -> Option<RedirectionEntry> {
+    with_gsi(gsi, |ioapic, index| ioapic.entry(index))
+}
+pub fn mask_gsi(gsi: u32) -> bool {
+    with_gsi(gsi, |ioapic, index| ioapic.mask(index)).is_some()
+}
+pub fn unmask_gsi(gsi: u32) -> bool {
+    with_gsi(gsi, |ioapic, index| ioapic.unmask(index)).is_some()
+}
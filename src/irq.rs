@@ -0,0 +1,164 @@
+use bitflags::bitflags;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::acpi;
+use crate::apic::{self, APIC_BASE, write_apic_register};
+use crate::ioapic::{self, RedirectionEntry};
+use crate::sync::{self, IrqMutex};
+
+// Vectors handed out by register_irq. Everything below is taken by exceptions, the
+// remapped legacy PICs, the LAPIC timer and the LVT error vector.
+pub const IRQ_VECTOR_BASE: u8 = 0x40;
+const IRQ_VECTORS: usize = 32;
+const MAX_SHARED_HANDLERS: usize = 4;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct IrqFlags: u32 {
+        // Level triggered instead of edge triggered
+        const LEVEL = 1 << 0;
+        // Active low instead of active high
+        const ACTIVE_LOW = 1 << 1;
+        // Leave the line masked after registering; see unmask_irq
+        const MASKED = 1 << 2;
+    }
+}
+
+pub type IrqHandler = fn();
+
+#[derive(Debug)]
+pub enum IrqError {
+    NoFreeVector,
+    TooManyHandlers,
+    // The line is already registered with a different trigger mode or polarity
+    FlagsMismatch,
+    // No IOAPIC serves the requested GSI
+    NoIoApic,
+}
+
+#[derive(Clone, Copy)]
+struct IrqLine {
+    gsi: u32,
+    flags: IrqFlags,
+    handlers: [Option<IrqHandler>; MAX_SHARED_HANDLERS],
+}
+
+static LINES: IrqMutex<[Option<IrqLine>; IRQ_VECTORS]> = IrqMutex::new([None; IRQ_VECTORS]);
+
+// Routes `gsi` to the next free vector (or the one it already has) and adds `handler` to
+// the handlers run for it. Returns the vector the line is delivered on.
+pub fn register_irq(gsi: u32, handler: IrqHandler, flags: IrqFlags) -> Result<u8, IrqError> {
+    let mut lines = LINES.lock();
+    if let Some((index, line)) = lines
+        .iter_mut()
+        .enumerate()
+        .find_map(|(i, l)| l.as_mut().filter(|l| l.gsi == gsi).map(|l| (i, l)))
+    {
+        let routing = IrqFlags::LEVEL | IrqFlags::ACTIVE_LOW;
+        if line.flags & routing != flags & routing {
+            return Err(IrqError::FlagsMismatch);
+        }
+        let slot = line
+            .handlers
+            .iter_mut()
+            .find(|h| h.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(handler);
+        return Ok(IRQ_VECTOR_BASE + index as u8);
+    }
+    let index = lines
+        .iter()
+        .position(|l| l.is_none())
+        .ok_or(IrqError::NoFreeVector)?;
+    let mut handlers = [None; MAX_SHARED_HANDLERS];
+    handlers[0] = Some(handler);
+    let vector = IRQ_VECTOR_BASE + index as u8;
+    route(gsi, vector, flags)?;
+    lines[index] = Some(IrqLine {
+        gsi,
+        flags,
+        handlers,
+    });
+    Ok(vector)
+}
+
+// Registers a handler for a legacy ISA IRQ, following the MADT's interrupt source
+// overrides for the GSI, polarity and trigger mode it is really wired to. Only MASKED is
+// taken from `flags`.
+pub fn register_isa_irq(irq: u8, handler: IrqHandler, flags: IrqFlags) -> Result<u8, IrqError> {
+    let masked = flags & IrqFlags::MASKED;
+    let (gsi, flags) = match acpi::info().map(|info| info.isa_irq(irq)) {
+        Some(wiring) => {
+            let mut flags = masked;
+            flags.set(IrqFlags::ACTIVE_LOW, wiring.active_low);
+            flags.set(IrqFlags::LEVEL, wiring.level_triggered);
+            (wiring.gsi, flags)
+        }
+        None => (irq as u32, masked),
+    };
+    register_irq(gsi, handler, flags)
+}
+
+// Programs the IOAPIC redirection entry for `gsi`, delivering to this CPU's LAPIC.
+fn route(gsi: u32, vector: u8, flags: IrqFlags) -> Result<(), IrqError> {
+    let mut entry = RedirectionEntry::new(vector, apic::lapic_id());
+    entry.active_low = flags.contains(IrqFlags::ACTIVE_LOW);
+    entry.level_triggered = flags.contains(IrqFlags::LEVEL);
+    entry.masked = flags.contains(IrqFlags::MASKED);
+    if ioapic::set_gsi(gsi, entry) {
+        Ok(())
+    } else {
+        Err(IrqError::NoIoApic)
+    }
+}
+fn line_gsi(vector: u8) -> Option<u32> {
+    let index = vector.checked_sub(IRQ_VECTOR_BASE)? as usize;
+    LINES.lock().get(index)?.map(|line| line.gsi)
+}
+// Masks the line delivered on `vector`, as returned by register_irq. Returns false if no
+// line is registered there.
+pub fn mask_irq(vector: u8) -> bool {
+    line_gsi(vector).is_some_and(ioapic::mask_gsi)
+}
+pub fn unmask_irq(vector: u8) -> bool {
+    line_gsi(vector).is_some_and(ioapic::unmask_gsi)
+}
+// The IOAPIC redirection entry currently programmed for the line on `vector`.
+pub fn irq_entry(vector: u8) -> Option<RedirectionEntry> {
+    line_gsi(vector).and_then(ioapic::gsi_entry)
+}
+
+// Runs every handler registered on the vector, then acknowledges the interrupt.
+fn dispatch(vector: u8) {
+    let _irq = sync::enter_interrupt();
+    // Copied out so handlers can register further IRQs without deadlocking
+    let line = LINES.lock()[(vector - IRQ_VECTOR_BASE) as usize];
+    if let Some(line) = line {
+        for handler in line.handlers.iter().flatten() {
+            handler();
+        }
+    }
+    unsafe {
+        write_apic_register(APIC_BASE, 0x0B0, 0);
+    }
+}
+extern "x86-interrupt" fn irq_stub<const VECTOR: u8>(_stackframe: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+macro_rules! irq_stubs {
+    ($($offset:literal),*) => {
+        [$(irq_stub::<{ IRQ_VECTOR_BASE + $offset }> as HandlerFunc),*]
+    };
+}
+static IRQ_STUBS: [HandlerFunc; IRQ_VECTORS] = irq_stubs!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+    26, 27, 28, 29, 30, 31
+);
+
+// Points every dynamic vector at its dispatch stub.
+pub fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    for (i, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[IRQ_VECTOR_BASE + i as u8].set_handler_fn(*stub);
+    }
+}
@@ -1,9 +1,13 @@
 use crate::console::backspace;
-use crate::{
-    apic::{ioapic_read, ioapic_write},
-    print, println,
-};
+use crate::time;
+use crate::{print, println};
+use core::fmt;
+use core::time::Duration;
 const BUFFER_SIZE: usize = 256;
+const KEYBOARD_IRQ: u8 = 1;
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+// Devices can take several hundred milliseconds to finish their reset self test
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
 // PS/2 Scan Code Set 2 to USB HID mapping
 static SCANCODE_TO_HID: [u8; 256] = [
     0, 0, 0, 0, 0, 0, 0, 0, // 0x00-0x07
@@ -190,10 +194,40 @@ static HID_TO_ASCII_SHIFT: [u8; 256] = [
     0, 0, 0, 0, 0, 0, 0, 0, // 0xF8-0xFF
 ];
 
-use spin::mutex::Mutex;
+use crate::irq::{self, IrqFlags};
+use crate::sync::IrqMutex;
 use x86_64::instructions::port::Port;
-static KEYBOARD_BUFFER: Mutex<KeyboardBuffer> = Mutex::new(KeyboardBuffer::new());
-pub static KEYBOARDKEY_STATE: Mutex<Option<KeyboardKeyState>> = Mutex::new(None);
+static KEYBOARD_BUFFER: IrqMutex<KeyboardBuffer> = IrqMutex::new(KeyboardBuffer::new());
+pub static KEYBOARDKEY_STATE: IrqMutex<Option<KeyboardKeyState>> = IrqMutex::new(None);
+
+#[derive(Debug)]
+pub enum KeyboardError {
+    // The controller never produced a byte to read
+    OutputTimeout,
+    // The controller never drained its input buffer
+    InputTimeout,
+    SelfTestFailed(u8),
+    // The IOAPIC line could not be unmasked
+    IrqUnmaskFailed(u8),
+}
+impl fmt::Display for KeyboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyboardError::OutputTimeout => write!(f, "controller never produced a byte"),
+            KeyboardError::InputTimeout => write!(f, "controller never drained its input"),
+            KeyboardError::SelfTestFailed(byte) => {
+                write!(f, "controller self test returned {:#04x}", byte)
+            }
+            KeyboardError::IrqUnmaskFailed(vector) => {
+                write!(
+                    f,
+                    "IRQ line on vector {:#04x} could not be unmasked",
+                    vector
+                )
+            }
+        }
+    }
+}
 
 pub struct Keyboard {
     data_port: Port<u8>,      //r/w
@@ -208,7 +242,25 @@ impl Keyboard {
             command_port: Port::new(0x64),
         }
     }
-    pub fn init(&mut self) {
+    // Returns the vector the keyboard interrupts on.
+    pub fn init(&mut self) -> Result<u8, KeyboardError> {
+        // Routed up front but kept masked until the controller is set up
+        let vector = match irq::register_isa_irq(KEYBOARD_IRQ, keyboard_irq, IrqFlags::MASKED) {
+            Ok(vector) => vector,
+            Err(e) => panic!("[Error] Keyboard IRQ registration failed: {:?}", e),
+        };
+        let result = self.setup(vector).map(|()| vector);
+        if result.is_err() {
+            // Setup starts by disabling both ports; turn them back on rather than leave
+            // the controller dead
+            unsafe {
+                self.command_port.write(0xAE);
+                self.command_port.write(0xA8);
+            }
+        }
+        result
+    }
+    fn setup(&mut self, vector: u8) -> Result<(), KeyboardError> {
         unsafe {
             //disable PS/2
             self.command_port.write(0xAD);
@@ -219,55 +271,69 @@ impl Keyboard {
             }
             //Setup Controller byte
             self.command_port.write(0x20);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             let byte = self.data_port.read();
             let mask = (1 << 0) | (1 << 4) | (1 << 6);
             self.command_port.write(0x60);
-            self.poll_for_input();
+            self.poll_for_input()?;
             self.data_port.write(byte & (!mask));
             //self test
             self.command_port.write(0xAA);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             let byte = self.data_port.read();
             if byte != 0x55 {
-                panic!("[Error] Keyboard Failed to Init");
+                return Err(KeyboardError::SelfTestFailed(byte));
             }
             //detemine if dual channel
             self.command_port.write(0xA8);
             self.command_port.write(0x20);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             let has_dual = (self.data_port.read() & (1 << 5)) >> 5;
             //perform interface test
             self.command_port.write(0xAB);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             let byte = self.data_port.read();
             //enable devices
             self.command_port.write(0xAE);
             self.command_port.write(0x20);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             let new_byte = (self.data_port.read() | 1);
             self.command_port.write(0x60);
-            self.poll_for_input();
+            self.poll_for_input()?;
             self.data_port.write(new_byte);
             //reset devices
-            self.poll_for_input();
+            self.poll_for_input()?;
             self.data_port.write(0xFF);
-            self.poll_for_ouput();
-            self.poll_for_ouput();
+            self.poll_for_ouput(RESET_TIMEOUT)?;
+            self.poll_for_ouput(RESET_TIMEOUT)?;
             //enable scanning
-            self.poll_for_input();
+            self.poll_for_input()?;
             self.data_port.write(0xF4);
-            self.poll_for_ouput();
+            self.poll_for_ouput(POLL_TIMEOUT)?;
             self.data_port.read();
             KeyboardKeyState::init();
-            println!("[OK] Keyboard Driver is active")
+            if !irq::unmask_irq(vector) {
+                return Err(KeyboardError::IrqUnmaskFailed(vector));
+            }
+            println!("[OK] Keyboard Driver is active");
+            Ok(())
         }
     }
-    fn poll_for_ouput(&mut self) {
-        unsafe { while self.status_registr.read() & 0x01 == 0 {} }
+    fn poll_for_ouput(&mut self, timeout: Duration) -> Result<(), KeyboardError> {
+        let status = &mut self.status_registr;
+        if time::poll_until(timeout, || unsafe { status.read() } & 0x01 != 0) {
+            Ok(())
+        } else {
+            Err(KeyboardError::OutputTimeout)
+        }
     }
-    fn poll_for_input(&mut self) {
-        unsafe { while self.status_registr.read() & 0x02 != 0 {} }
+    fn poll_for_input(&mut self) -> Result<(), KeyboardError> {
+        let status = &mut self.status_registr;
+        if time::poll_until(POLL_TIMEOUT, || unsafe { status.read() } & 0x02 == 0) {
+            Ok(())
+        } else {
+            Err(KeyboardError::InputTimeout)
+        }
     }
 }
 pub struct KeyboardKeyState {
@@ -311,6 +377,11 @@ impl KeyboardKeyState {
         self.left_alt || self.right_alt
     }
 }
+fn keyboard_irq() {
+    let mut data_port = Port::<u8>::new(0x60);
+    let scancode = unsafe { data_port.read() };
+    handle_scancode(scancode);
+}
 pub fn handle_scancode(code: u8) {
     if let Some(ref mut state) = *KEYBOARDKEY_STATE.lock() {
         // PS/2 Set 2: 0xF0 indicates next scancode is a key release
@@ -3,19 +3,31 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 //mod allocator;
+mod acpi;
 mod allocator_types;
 mod apic;
+mod backtrace;
+mod buddy;
 mod console;
+mod demand;
 mod framebuffer;
 pub mod gdt;
+mod guard;
 mod interupts;
+mod ioapic;
+mod irq;
 mod keyboard;
 mod memory;
+mod peparser;
 mod psfparser;
+mod sync;
+mod time;
+mod timer;
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 use core::u64;
 mod virtualmapper;
+mod vmalloc;
 use framebuffer::{FrameBuffer, FrameBufferInfo};
 use keyboard::Keyboard;
 use psfparser::psffont;
@@ -23,15 +35,30 @@ use uefi::boot::*;
 use uefi::mem::memory_map::{MemoryMap, MemoryType};
 use uefi::prelude::*;
 use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::loaded_image::LoadedImage;
 static FONT_DATA: &[u8] = include_bytes!("../fonts/Lat2-Terminus16.psfu");
 use spin::Once;
 use x86_64::PhysAddr;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
 extern crate alloc;
-use crate::memory::{BitmapFrameAllocator, init_frame_allocator, with_frame_allocator};
+use crate::backtrace::SymbolFile;
+use crate::buddy::BuddyAllocator;
+use crate::memory::{
+    BitmapFrameAllocator, KernelImage, ReclaimStage, Zone, init_frame_allocator,
+    with_frame_allocator,
+};
 use crate::virtualmapper::{map_heap, map_physical_to_virtual};
-static FONT: Once<psffont> = Once::new();
+// What kernel_main leaves identity mapped for the higher-half code to tear down
+#[derive(Clone, Copy)]
+struct BootMappings {
+    image: KernelImage,
+    boot_stack: (u64, u64),
+}
+static BOOT_MAPPINGS: Once<BootMappings> = Once::new();
 static BLACK: u32 = 0x000000;
+const KERNEL_STACK_PAGES: u64 = 32;
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!(
@@ -44,9 +71,22 @@ pub fn init() {
     gdt::init();
     interupts::init_idt();
     apic::init();
-    let mut keyboard = Keyboard::new();
-    keyboard.init();
+    time::init();
+    // Enabled before the keyboard so its timeouts work off timer ticks without a TSC
     x86_64::instructions::interrupts::enable();
+    let mut keyboard = Keyboard::new();
+    match keyboard.init() {
+        Ok(vector) => {
+            // Round-trip the mask bit through the IOAPIC entry
+            assert!(irq::mask_irq(vector));
+            assert!(irq::irq_entry(vector).is_some_and(|entry| entry.masked));
+            assert!(irq::unmask_irq(vector));
+            let entry = irq::irq_entry(vector).expect("Keyboard IRQ entry vanished");
+            assert!(entry.vector == vector && !entry.masked);
+            println!("[OK] Keyboard IRQ entry masks and unmasks");
+        }
+        Err(e) => println!("[ERROR] Keyboard init failed, ports left enabled: {}", e),
+    }
 }
 pub fn hlt_loop() -> ! {
     loop {
@@ -71,13 +111,33 @@ pub fn find_largest_region(mmap: &uefi::mem::memory_map::MemoryMapOwned) -> Opti
         None
     }
 }
-pub fn handle_memory(mmap: &uefi::mem::memory_map::MemoryMapOwned) {
+// The firmware stack is a single allocation, so the memory map descriptor containing
+// RSP covers all of it. Neighbouring allocations of the same type may be merged into that
+// descriptor, which only makes the protected range larger than needed.
+pub fn boot_stack_range(mmap: &uefi::mem::memory_map::MemoryMapOwned) -> (u64, u64) {
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp);
+    }
+    mmap.entries()
+        .map(|desc| (desc.phys_start, desc.phys_start + desc.page_count * 4096))
+        .find(|&(start, end)| rsp >= start && rsp < end)
+        .unwrap_or_else(|| panic!("Boot stack at {:#x} is not in the memory map", rsp))
+}
+pub fn handle_memory(
+    mmap: &uefi::mem::memory_map::MemoryMapOwned,
+    image: KernelImage,
+    boot_stack: (u64, u64),
+    symbols: Option<SymbolFile>,
+) {
     let mut total_pages = 0;
     for desc in mmap.entries() {
         total_pages += desc.page_count
     }
     let bitmap_field_size = (total_pages + 63) / 64;
     let bitmap_size = bitmap_field_size * 8;
+    let buddy_size = BuddyAllocator::metadata_size(total_pages as usize) as u64;
+    let metadata_size = bitmap_size + buddy_size;
 
     let (region_start, region_size) = match find_largest_region(mmap) {
         Some(region) => region,
@@ -87,7 +147,7 @@ pub fn handle_memory(mmap: &uefi::mem::memory_map::MemoryMapOwned) {
         }
     };
 
-    if region_size < bitmap_size {
+    if region_size < metadata_size {
         println!("ERROR: Largest memory region too small for bitmap allocator");
         return;
     }
@@ -99,21 +159,59 @@ pub fn handle_memory(mmap: &uefi::mem::memory_map::MemoryMapOwned) {
         }
     }
 
-    let mut allocator =
-        BitmapFrameAllocator::new(bitmap_ptr, bitmap_field_size as usize, total_pages as usize);
+    let buddy_ptr = (region_start + bitmap_size) as *mut u8;
+    let buddy = unsafe { BuddyAllocator::new(buddy_ptr, total_pages as usize) };
+
+    let mut allocator = BitmapFrameAllocator::new(
+        bitmap_ptr,
+        bitmap_field_size as usize,
+        total_pages as usize,
+        buddy,
+    );
 
     for desc in mmap.entries() {
+        allocator.record_region(desc.ty, desc.page_count as usize);
         if desc.ty == MemoryType::CONVENTIONAL
             || desc.ty == MemoryType::BOOT_SERVICES_CODE
             || desc.ty == MemoryType::BOOT_SERVICES_DATA
         {
             let start_frame = (desc.phys_start / 4096) as usize;
             let frame_count = desc.page_count as usize;
-            allocator.mark_range_free(start_frame, frame_count);
+            allocator.add_usable_range(start_frame, frame_count);
+        } else if desc.ty == MemoryType::ACPI_RECLAIM {
+            let start_frame = (desc.phys_start / 4096) as usize;
+            allocator.record_reclaimable(
+                ReclaimStage::AcpiTables,
+                start_frame,
+                desc.page_count as usize,
+            );
+        } else if desc.ty == MemoryType::LOADER_CODE || desc.ty == MemoryType::LOADER_DATA {
+            let start_frame = (desc.phys_start / 4096) as usize;
+            allocator.record_reclaimable(
+                ReclaimStage::LoaderData,
+                start_frame,
+                desc.page_count as usize,
+            );
         }
     }
 
-    let bitmap_frames = (bitmap_size + 4095) / 4096;
+    let image_start_frame = (image.base / 4096) as usize;
+    let image_frames = (image.base + image.size).div_ceil(4096) as usize - image_start_frame;
+    allocator.protect_range(image_start_frame, image_frames);
+
+    // The boot stack handed to us by the firmware lives in BOOT_SERVICES_DATA, which was
+    // just freed above, so it has to be taken out of the allocator again.
+    let stack_start_frame = (boot_stack.0 / 4096) as usize;
+    let stack_frames = ((boot_stack.1 - boot_stack.0) / 4096) as usize;
+    allocator.protect_range(stack_start_frame, stack_frames);
+
+    // The symbol table sits in LOADER_DATA, which is reclaimed once the direct map is up
+    if let Some(symbols) = symbols {
+        let symbols_start_frame = (symbols.phys / 4096) as usize;
+        allocator.protect_range(symbols_start_frame, symbols.len.div_ceil(4096));
+    }
+
+    let bitmap_frames = (metadata_size + 4095) / 4096;
     let bitmap_start_frame = (region_start / 4096) as usize;
     allocator.mark_range_used(bitmap_start_frame, bitmap_frames as usize);
     allocator.mark_range_used(0, 256);
@@ -125,6 +223,16 @@ fn efi_main() -> Status {
     let gop_handle = boot::get_handle_for_protocol::<GraphicsOutput>().expect("Cannot load");
     let mut gop =
         boot::open_protocol_exclusive::<GraphicsOutput>(gop_handle).expect("Cannot get gop");
+    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+        .expect("Cannot get loaded image");
+    let (image_base, image_size) = loaded_image.info();
+    let image = KernelImage {
+        base: image_base as u64,
+        size: image_size,
+    };
+    drop(loaded_image);
+    let symbols = backtrace::load_symbol_file();
+    let rsdp = acpi::find_rsdp();
     let mode_info = gop.current_mode_info();
     let mut framebuff_raw = gop.frame_buffer();
     let frame_info = FrameBufferInfo {
@@ -137,21 +245,75 @@ fn efi_main() -> Status {
     };
     let mmap = unsafe { exit_boot_services(Some(MemoryType::LOADER_DATA)) };
 
-    kernel_main(mmap, frame_info);
+    kernel_main(mmap, frame_info, image, symbols, rsdp);
 }
 
-fn kernel_main(mmap: uefi::mem::memory_map::MemoryMapOwned, fbinfo: FrameBufferInfo) -> ! {
+fn kernel_main(
+    mmap: uefi::mem::memory_map::MemoryMapOwned,
+    fbinfo: FrameBufferInfo,
+    image: KernelImage,
+    symbols: Option<SymbolFile>,
+    rsdp: Option<u64>,
+) -> ! {
     let fb = FrameBuffer::new(fbinfo);
     let font = match psffont::parse(FONT_DATA) {
         Ok(f) => f,
         Err(_) => loop {},
     };
-    FONT.call_once(|| font);
-    let font_ref = FONT.get().unwrap();
-    console::Console::init(fb, font_ref);
-    handle_memory(&mmap);
-    map_physical_to_virtual(&mmap, fbinfo.addr as u64, fbinfo.size);
+    console::Console::init(fb, font);
+    let boot_stack = boot_stack_range(&mmap);
+    handle_memory(&mmap, image, boot_stack, symbols);
+    backtrace::init(image, symbols);
+    match symbols {
+        Some(symbols) => println!("[OK] Loaded {} KiB kernel symbol table", symbols.len / 1024),
+        None => println!("[WARN] No kernel.sym on the ESP, backtraces will not be symbolized"),
+    }
+    // Parsed while the firmware identity map is still live; everything needed later is
+    // copied out, so the tables can be reclaimed once the kernel page tables are up.
+    let acpi = match rsdp.map(acpi::init) {
+        Some(Ok(info)) => {
+            info.print();
+            Some(info)
+        }
+        Some(Err(e)) => {
+            println!("[ERROR] ACPI tables unusable: {}", e);
+            None
+        }
+        None => {
+            println!("[WARN] No ACPI RSDP in the UEFI configuration table");
+            None
+        }
+    };
+    let lapic_phys = acpi.map_or(apic::LAPIC_PHYS_BASE, |info| info.lapic_address);
+    map_physical_to_virtual(
+        &mmap,
+        fbinfo.addr as u64,
+        fbinfo.size,
+        image,
+        boot_stack,
+        lapic_phys,
+    );
+    drop(mmap);
+    let reclaimed =
+        with_frame_allocator(|allocator| allocator.reclaim(ReclaimStage::LoaderData)).unwrap_or(0);
+    println!("[OK] Reclaimed {} KiB of loader memory", reclaimed * 4);
+    if acpi.is_some() {
+        let reclaimed =
+            with_frame_allocator(|allocator| allocator.reclaim(ReclaimStage::AcpiTables))
+                .unwrap_or(0);
+        println!("[OK] Reclaimed {} KiB of ACPI tables", reclaimed * 4);
+    }
+    BOOT_MAPPINGS.call_once(|| BootMappings { image, boot_stack });
+    unsafe { virtualmapper::enter_higher_half(image, kernel_main_high) }
+}
 
+// First code to run from KERNEL_BASE, still on the firmware stack.
+extern "C" fn kernel_main_high() -> ! {
+    // The font parsed earlier points at the load address, which is about to go away
+    match psffont::parse(FONT_DATA) {
+        Ok(font) => console::relocate_font(font),
+        Err(e) => panic!("Font no longer parses after relocation: {}", e),
+    }
     map_heap(
         allocator_types::linked_list::HEAP_START as u64,
         allocator_types::linked_list::HEAP_SIZE,
@@ -160,7 +322,49 @@ fn kernel_main(mmap: uefi::mem::memory_map::MemoryMapOwned, fbinfo: FrameBufferI
     unsafe {
         allocator_types::init();
     }
+    guard::protect_heap(
+        allocator_types::linked_list::HEAP_START as u64,
+        allocator_types::linked_list::HEAP_MAX_SIZE as u64,
+    );
     println!("[OK] Heap allocator initialized");
+
+    // Leave the firmware stack for one we own, with a guard page beneath it
+    let stack = guard::alloc_demand_stack("kernel main", KERNEL_STACK_PAGES);
+    unsafe { guard::switch_to(&stack, kernel_main_stack) }
+}
+
+extern "C" fn kernel_main_stack() -> ! {
+    let boot = BOOT_MAPPINGS.get().expect("Boot mappings not recorded");
+    virtualmapper::drop_identity_map(boot.image, boot.boot_stack);
+    // Nothing runs on or points into the firmware stack any more
+    let stack_frames = with_frame_allocator(|allocator| {
+        allocator.release_protected(
+            (boot.boot_stack.0 / 4096) as usize,
+            ((boot.boot_stack.1 - boot.boot_stack.0) / 4096) as usize,
+        )
+    })
+    .unwrap_or(0);
+    println!("[OK] Reclaimed {} KiB of boot stack", stack_frames * 4);
+    if let Some(stats) = with_frame_allocator(|allocator| allocator.stats()) {
+        stats.print();
+    }
+    let dma32 = with_frame_allocator(|allocator| allocator.allocate_frame_in(Zone::Dma32))
+        .flatten()
+        .expect("No free DMA32 frame");
+    assert!(dma32.start_address().as_u64() < 0x1_0000_0000);
+    with_frame_allocator(|allocator| unsafe { allocator.deallocate_frame(dma32) });
+    // ISA DMA wants 64 KiB that are 64 KiB aligned and end below 16 MiB
+    let isa = with_frame_allocator(|allocator| allocator.allocate_contiguous(16, 16, 0x100_0000))
+        .flatten()
+        .expect("No ISA DMA buffer below 16 MiB");
+    let isa_addr = isa.start_address().as_u64();
+    assert!(isa_addr.is_multiple_of(0x1_0000) && isa_addr + 0x1_0000 <= 0x100_0000);
+    with_frame_allocator(|allocator| allocator.deallocate_contiguous(isa, 16));
+    println!(
+        "[OK] Zone allocation: DMA32 frame {:#x}, ISA DMA buffer {:#x}",
+        dma32.start_address().as_u64(),
+        isa_addr
+    );
     println!("=== KitsuneOS Boot ===");
     println!();
     println!();
@@ -168,6 +372,15 @@ fn kernel_main(mmap: uefi::mem::memory_map::MemoryMapOwned, fbinfo: FrameBufferI
     println!("[OK] PSF font loaded successfully");
     println!("[OK] Console initialized");
     init();
+    let before = time::Instant::now();
+    time::sleep_ms(10);
+    time::udelay(100);
+    println!(
+        "[OK] Slept {} us, uptime {} ms, {} timer ticks",
+        before.elapsed().as_micros(),
+        time::uptime().as_millis(),
+        time::ticks()
+    );
     println!();
 
     println!("===Welcome to KitsuneOS!===");
@@ -187,7 +400,58 @@ fn kernel_main(mmap: uefi::mem::memory_map::MemoryMapOwned, fbinfo: FrameBufferI
     drop(boxes);
     let new_box = Box::new(999);
     assert_eq!(*new_box, 999);
+    let big: Vec<u8> = alloc::vec![0xA5; 6 * 1024 * 1024];
+    assert!(big.iter().all(|&b| b == 0xA5));
+    drop(big);
+    let released = allocator_types::shrink_heap();
+    assert!(released > 0, "Heap did not release any of its growth");
+    println!(
+        "[OK] Heap grew past its initial size, {} KiB released",
+        released / 1024
+    );
+    let mut words: Vec<u64> = (0..4096).collect();
+    let before = words.as_ptr();
+    words.truncate(1024);
+    words.shrink_to_fit();
+    words.reserve_exact(3072);
+    assert!(words.iter().copied().eq(0..1024));
+    // Red zones sit right behind the data, so with heap debugging every realloc moves
+    if !allocator_types::debug::DEBUG_ENABLED {
+        assert_eq!(
+            words.as_ptr(),
+            before,
+            "Vec shrink and regrow moved the block"
+        );
+    }
+    // Too small a cut to split off, so the block moves and no bytes may go missing
+    let free_before = allocator_types::heap_stats().free_bytes;
+    let mut bytes: Vec<u8> = Vec::with_capacity(4096);
+    bytes.shrink_to(4088);
+    drop(bytes);
+    assert_eq!(
+        allocator_types::heap_stats().free_bytes,
+        free_before,
+        "Vec shrink by 8 bytes lost heap memory"
+    );
+    println!("[OK] Heap realloc stays in place");
+    drop(words);
+    allocator_types::leak::start();
+    let tracked = Box::new([0u8; 64]);
+    assert_eq!(allocator_types::leak::live_count(), 1);
+    allocator_types::leak::dump();
+    drop(tracked);
+    allocator_types::leak::stop();
+    assert_eq!(allocator_types::leak::live_count(), 0);
+    allocator_types::heap_stats().print();
     println!("[OK] Passed Heap Test");
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let area =
+        vmalloc::vmalloc(3 * 4096, flags).unwrap_or_else(|e| panic!("vmalloc failed: {}", e));
+    let words = unsafe { core::slice::from_raw_parts_mut(area.as_mut_ptr::<u64>(), 3 * 512) };
+    assert!(words.iter().all(|&w| w == 0));
+    words.fill(0xABCD);
+    vmalloc::vfree(area, 3 * 4096);
+    println!("[OK] Passed vmalloc Test");
     hlt_loop();
 }
 
@@ -196,5 +460,6 @@ fn panic(info: &PanicInfo) -> ! {
     println!();
     println!("!!! KERNEL PANIC !!!");
     println!("{}", info);
+    backtrace::print_backtrace();
     hlt_loop();
 }
@@ -1,11 +1,147 @@
+use crate::sync::IrqMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use uefi::mem::memory_map::MemoryType;
 use x86_64::PhysAddr;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+
+use crate::buddy::{BuddyAllocator, MAX_ORDER};
+use crate::println;
 pub struct FrameAllocatorWrapper;
 
+pub const ZONE_COUNT: usize = 3;
+const DMA16_LIMIT: usize = 0x100_0000 / 4096;
+const DMA32_LIMIT: usize = 0x1_0000_0000 / 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Dma16 = 0,
+    Dma32 = 1,
+    Normal = 2,
+}
+impl Zone {
+    pub const ALL: [Zone; ZONE_COUNT] = [Zone::Dma16, Zone::Dma32, Zone::Normal];
+
+    pub fn of_frame(frame: usize) -> Zone {
+        if frame < DMA16_LIMIT {
+            Zone::Dma16
+        } else if frame < DMA32_LIMIT {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+    // Highest zone that lies entirely below `max_phys_addr`, or None when even DMA16
+    // reaches past it. Normal has no upper end, so only an unbounded limit selects it.
+    pub fn below(max_phys_addr: u64) -> Option<Zone> {
+        if max_phys_addr == u64::MAX {
+            Some(Zone::Normal)
+        } else if max_phys_addr >= (DMA32_LIMIT * 4096) as u64 {
+            Some(Zone::Dma32)
+        } else if max_phys_addr >= (DMA16_LIMIT * 4096) as u64 {
+            Some(Zone::Dma16)
+        } else {
+            None
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Zone::Dma16 => "DMA16",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneInfo {
+    pub zone: Zone,
+    pub usable_frames: usize,
+    pub free_frames: usize,
+}
+
+// Number of standard UEFI memory types tracked individually; everything above (OEM and
+// OS loader defined types) is folded into the last slot.
+pub const MEMORY_TYPE_COUNT: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+    pub reserved_frames: usize,
+    pub metadata_bytes: usize,
+    pub largest_free_run: usize,
+    pub zones: [ZoneInfo; ZONE_COUNT],
+    pub type_frames: [usize; MEMORY_TYPE_COUNT],
+}
+impl MemoryStats {
+    pub fn print(&self) {
+        println!("=== Memory ===");
+        println!(
+            "Total    {:>8} frames {:>6} MiB",
+            self.total_frames,
+            self.total_frames / 256
+        );
+        println!(
+            "Free     {:>8} frames {:>6} MiB",
+            self.free_frames,
+            self.free_frames / 256
+        );
+        println!(
+            "Used     {:>8} frames {:>6} MiB",
+            self.used_frames.saturating_sub(self.reserved_frames),
+            self.used_frames.saturating_sub(self.reserved_frames) / 256
+        );
+        println!(
+            "Reserved {:>8} frames {:>6} MiB",
+            self.reserved_frames,
+            self.reserved_frames / 256
+        );
+        println!(
+            "Allocator metadata {} KiB, largest free run {} frames",
+            self.metadata_bytes / 1024,
+            self.largest_free_run
+        );
+        for zone in self.zones.iter() {
+            println!(
+                "Zone {:<6} {:>8} usable {:>8} free",
+                zone.zone.name(),
+                zone.usable_frames,
+                zone.free_frames
+            );
+        }
+        for (ty, frames) in self.type_frames.iter().enumerate() {
+            if *frames != 0 {
+                println!("  {:<24?} {:>8} frames", MemoryType(ty as u32), frames);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage {
+    pub base: u64,
+    pub size: u64,
+}
+
+const MAX_RECLAIM_RANGES: usize = 64;
+const MAX_PROTECTED_RANGES: usize = 8;
+
+// Memory that is still in use right after boot but can be handed to the allocator once
+// its consumer is done with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReclaimStage {
+    AcpiTables,
+    LoaderData,
+}
+#[derive(Clone, Copy)]
+struct ReclaimRange {
+    stage: ReclaimStage,
+    start_frame: usize,
+    count: usize,
+}
+
 lazy_static! {
-    static ref FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+    static ref FRAME_ALLOCATOR: IrqMutex<Option<BitmapFrameAllocator>> = IrqMutex::new(None);
 }
 
 pub fn init_frame_allocator(allocator: BitmapFrameAllocator) {
@@ -22,17 +158,34 @@ where
         None
     }
 }
+// For the page fault handler, which must not spin on a lock the faulting code may hold.
+pub fn try_with_frame_allocator<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    FRAME_ALLOCATOR.try_lock()?.as_mut().map(f)
+}
 unsafe impl FrameAllocator<Size4KiB> for FrameAllocatorWrapper {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         with_frame_allocator(|alloc| alloc.allocate_frame()).flatten()
     }
 }
+impl FrameDeallocator<Size4KiB> for FrameAllocatorWrapper {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        with_frame_allocator(|alloc| unsafe { alloc.deallocate_frame(frame) });
+    }
+}
 pub struct BitmapFrameAllocator {
     pub bitmap_start: *mut u64,
     bitmap_len: usize,
     total_frames: usize,
     used_frames: usize,
-    next_free: usize,
+    usable_frames: [usize; ZONE_COUNT],
+    type_frames: [usize; MEMORY_TYPE_COUNT],
+    reclaim_ranges: [Option<ReclaimRange>; MAX_RECLAIM_RANGES],
+    protected: [(usize, usize); MAX_PROTECTED_RANGES],
+    protected_len: usize,
+    buddy: BuddyAllocator,
 }
 
 // SAFETY: We guarantee that the bitmap will only be accessed from one thread at a time
@@ -40,20 +193,48 @@ pub struct BitmapFrameAllocator {
 unsafe impl Send for BitmapFrameAllocator {}
 unsafe impl Sync for BitmapFrameAllocator {}
 
+// The bitmap stays the source of truth for which frames are in use, while the buddy
+// allocator mirrors every free frame so allocations no longer have to scan the bitmap.
 impl BitmapFrameAllocator {
-    pub fn new(start_addr: *mut u64, size_of_mem: usize, total_frame: usize) -> Self {
+    pub fn new(
+        start_addr: *mut u64,
+        size_of_mem: usize,
+        total_frame: usize,
+        buddy: BuddyAllocator,
+    ) -> Self {
         BitmapFrameAllocator {
             bitmap_start: start_addr,
             bitmap_len: size_of_mem,
             total_frames: total_frame,
             used_frames: total_frame,
-            next_free: 0,
+            usable_frames: [0; ZONE_COUNT],
+            type_frames: [0; MEMORY_TYPE_COUNT],
+            reclaim_ranges: [None; MAX_RECLAIM_RANGES],
+            protected: [(0, 0); MAX_PROTECTED_RANGES],
+            protected_len: 0,
+            buddy,
         }
     }
-    fn mark_freed(&mut self, frame: usize) {
-        if frame >= self.total_frames {
-            return;
+    // Moves the allocator metadata to the direct map once the kernel page tables are live.
+    pub fn relocate(&mut self, offset: u64) {
+        self.bitmap_start = (self.bitmap_start as u64 + offset) as *mut u64;
+        self.buddy.relocate(offset);
+    }
+    fn set_bit(&mut self, frame: usize) -> bool {
+        let index = frame / 64;
+        let bit = frame % 64;
+        unsafe {
+            let entry = self.bitmap_start.add(index);
+            let current = entry.read_volatile();
+            if (current & (1 << bit)) == 0 {
+                entry.write_volatile(current | (1 << bit));
+                self.used_frames += 1;
+                return true;
+            }
         }
+        false
+    }
+    fn clear_bit(&mut self, frame: usize) -> bool {
         let index = frame / 64;
         let bit = frame % 64;
         unsafe {
@@ -62,22 +243,25 @@ impl BitmapFrameAllocator {
             if (current & (1 << bit)) != 0 {
                 entry.write_volatile(current & !(1 << bit));
                 self.used_frames -= 1;
+                return true;
             }
         }
+        false
+    }
+    fn mark_freed(&mut self, frame: usize) {
+        if frame >= self.total_frames {
+            return;
+        }
+        if self.clear_bit(frame) {
+            self.buddy.free(frame, 0);
+        }
     }
     fn mark_used(&mut self, frame: usize) {
         if frame >= self.total_frames {
             return;
         }
-        let index = frame / 64;
-        let bit = frame % 64;
-        unsafe {
-            let entry = self.bitmap_start.add(index);
-            let current = entry.read_volatile();
-            if (current & (1 << bit)) == 0 {
-                entry.write_volatile(current | (1 << bit));
-                self.used_frames += 1;
-            }
+        if self.set_bit(frame) {
+            self.buddy.take(frame);
         }
     }
     pub fn mark_range_free(&mut self, start_frame: usize, count: usize) {
@@ -90,56 +274,249 @@ impl BitmapFrameAllocator {
             self.mark_used(frame);
         }
     }
-}
-unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        unsafe {
-            // Search from next_free to end
-            for i in self.next_free..self.bitmap_len {
-                let entry = self.bitmap_start.add(i).read_volatile();
-                if entry != u64::MAX {
-                    // Found a u64 with at least one free bit
-                    for bit in 0..64 {
-                        if (entry & (1 << bit)) == 0 {
-                            let frame_no = i * 64 + bit;
-                            if frame_no >= self.total_frames {
-                                continue; // Skip invalid frames, keep searching
-                            }
-                            self.mark_used(frame_no);
-                            self.next_free = i;
-                            let address = frame_no * 4096;
-                            let phys_addr = PhysAddr::new(address as u64);
-                            let frame: PhysFrame<Size4KiB> =
-                                PhysFrame::from_start_address(phys_addr).unwrap();
-                            return Some(frame);
-                        }
-                    }
+    // Frees a range reported as usable RAM by the firmware memory map and counts it
+    // towards its zone.
+    pub fn add_usable_range(&mut self, start_frame: usize, count: usize) {
+        for frame in start_frame..(start_frame + count).min(self.total_frames) {
+            self.usable_frames[Zone::of_frame(frame) as usize] += 1;
+        }
+        self.mark_range_free(start_frame, count);
+    }
+    // Marks a range as in use and keeps it out of every later reclaim stage.
+    pub fn protect_range(&mut self, start_frame: usize, count: usize) {
+        if self.protected_len == MAX_PROTECTED_RANGES {
+            panic!("Too many protected frame ranges");
+        }
+        self.protected[self.protected_len] = (start_frame, count);
+        self.protected_len += 1;
+        self.mark_range_used(start_frame, count);
+    }
+    fn is_protected(&self, frame: usize) -> bool {
+        self.protected[..self.protected_len]
+            .iter()
+            .any(|&(start, count)| frame >= start && frame < start + count)
+    }
+    // Drops a range given to protect_range and frees its frames, except those another
+    // protected range still covers. Returns the number of frames freed.
+    pub fn release_protected(&mut self, start_frame: usize, count: usize) -> usize {
+        let Some(index) = self.protected[..self.protected_len]
+            .iter()
+            .position(|&range| range == (start_frame, count))
+        else {
+            return 0;
+        };
+        self.protected
+            .copy_within(index + 1..self.protected_len, index);
+        self.protected_len -= 1;
+        let mut freed = 0;
+        for frame in start_frame..(start_frame + count).min(self.total_frames) {
+            if self.is_protected(frame) || !self.is_used(frame) {
+                continue;
+            }
+            self.mark_freed(frame);
+            freed += 1;
+        }
+        freed
+    }
+    pub fn record_reclaimable(&mut self, stage: ReclaimStage, start_frame: usize, count: usize) {
+        match self.reclaim_ranges.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(ReclaimRange {
+                    stage,
+                    start_frame,
+                    count,
+                })
+            }
+            None => println!(
+                "[WARN] Reclaim table full, frames {:#x}+{} stay reserved",
+                start_frame, count
+            ),
+        }
+    }
+    // Hands every range recorded for `stage` back to the allocator, skipping protected
+    // frames. Returns the number of frames reclaimed; calling it again is a no-op.
+    pub fn reclaim(&mut self, stage: ReclaimStage) -> usize {
+        let mut reclaimed = 0;
+        for i in 0..MAX_RECLAIM_RANGES {
+            let Some(range) = self.reclaim_ranges[i] else {
+                continue;
+            };
+            if range.stage != stage {
+                continue;
+            }
+            self.reclaim_ranges[i] = None;
+            let end = (range.start_frame + range.count).min(self.total_frames);
+            for frame in range.start_frame..end {
+                if self.is_protected(frame) || !self.is_used(frame) {
+                    continue;
                 }
+                self.usable_frames[Zone::of_frame(frame) as usize] += 1;
+                self.mark_freed(frame);
+                reclaimed += 1;
             }
-
-            // Wraparound: search from beginning to next_free
-            for i in 0..self.next_free {
-                let entry = self.bitmap_start.add(i).read_volatile();
-                if entry != u64::MAX {
-                    for bit in 0..64 {
-                        if (entry & (1 << bit)) == 0 {
-                            let frame_no = i * 64 + bit;
-                            if frame_no >= self.total_frames {
-                                continue;
-                            }
-                            self.mark_used(frame_no);
-                            self.next_free = i;
-                            let address = frame_no * 4096;
-                            let phys_addr = PhysAddr::new(address as u64);
-                            let frame: PhysFrame<Size4KiB> =
-                                PhysFrame::from_start_address(phys_addr).unwrap();
-                            return Some(frame);
-                        }
-                    }
+        }
+        reclaimed
+    }
+    pub fn record_region(&mut self, ty: MemoryType, page_count: usize) {
+        let index = (ty.0 as usize).min(MEMORY_TYPE_COUNT - 1);
+        self.type_frames[index] += page_count;
+    }
+    fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut current = 0;
+        for frame in 0..self.total_frames {
+            if self.is_used(frame) {
+                current = 0;
+            } else {
+                current += 1;
+                largest = largest.max(current);
+            }
+        }
+        largest
+    }
+    pub fn stats(&self) -> MemoryStats {
+        let usable: usize = self.usable_frames.iter().sum();
+        MemoryStats {
+            total_frames: self.total_frames,
+            free_frames: self.total_frames - self.used_frames,
+            used_frames: self.used_frames,
+            // Frames freed by release_protected may never have been counted as usable
+            reserved_frames: (self.total_frames - usable).min(self.used_frames),
+            metadata_bytes: self.bitmap_len * 8 + BuddyAllocator::metadata_size(self.total_frames),
+            largest_free_run: self.largest_free_run(),
+            zones: Zone::ALL.map(|zone| self.zone_info(zone)),
+            type_frames: self.type_frames,
+        }
+    }
+    pub fn zone_info(&self, zone: Zone) -> ZoneInfo {
+        ZoneInfo {
+            zone,
+            usable_frames: self.usable_frames[zone as usize],
+            free_frames: self.buddy.free_frames(zone),
+        }
+    }
+    fn is_used(&self, frame: usize) -> bool {
+        if frame >= self.total_frames {
+            return true;
+        }
+        let index = frame / 64;
+        let bit = frame % 64;
+        unsafe { (self.bitmap_start.add(index).read_volatile() & (1 << bit)) != 0 }
+    }
+    // Allocates a naturally aligned block of 2^order frames from `zone` or a lower zone.
+    pub fn allocate_order_in(&mut self, order: usize, zone: Zone) -> Option<PhysFrame<Size4KiB>> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let start = self.buddy.alloc(order, zone)?;
+        for frame in start..start + (1 << order) {
+            self.set_bit(frame);
+        }
+        PhysFrame::from_start_address(PhysAddr::new((start * 4096) as u64)).ok()
+    }
+    pub fn allocate_order(&mut self, order: usize) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_order_in(order, Zone::Normal)
+    }
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_order_in(0, zone)
+    }
+    // A block that is not entirely allocated is left alone, since handing it to the buddy
+    // allocator a second time would corrupt its free lists.
+    pub fn deallocate_order(&mut self, start: PhysFrame<Size4KiB>, order: usize) {
+        let start_frame = (start.start_address().as_u64() / 4096) as usize;
+        let end_frame = start_frame + (1 << order);
+        if end_frame > self.total_frames || (start_frame..end_frame).any(|f| !self.is_used(f)) {
+            println!(
+                "[WARN] Ignoring free of frames {:#x}+{} that are not all allocated",
+                start_frame,
+                1 << order
+            );
+            return;
+        }
+        for frame in start_frame..end_frame {
+            self.clear_bit(frame);
+        }
+        self.buddy.free(start_frame, order);
+    }
+    // Finds `count` physically contiguous free frames whose first frame is aligned to
+    // `align` frames and whose last byte lies below `max_phys_addr`.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_phys_addr: u64,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        // Fast path: carve the range out of a single buddy block and hand the tail back
+        let order = count.next_power_of_two().max(align).trailing_zeros() as usize;
+        if order <= MAX_ORDER
+            && let Some(zone) = Zone::below(max_phys_addr)
+            && let Some(start) = self.buddy.alloc(order, zone)
+        {
+            if ((start + count) * 4096) as u64 <= max_phys_addr {
+                for frame in start..start + count {
+                    self.set_bit(frame);
+                }
+                for frame in start + count..start + (1 << order) {
+                    self.buddy.free(frame, 0);
                 }
+                let phys_addr = PhysAddr::new((start * 4096) as u64);
+                return PhysFrame::from_start_address(phys_addr).ok();
             }
-
-            None
+            self.buddy.free(start, order);
         }
+        let frame_limit = ((max_phys_addr / 4096) as usize).min(self.total_frames);
+        let mut start = 0;
+        while start + count <= frame_limit {
+            // Skip fully used words quickly when we are at a word boundary
+            if start % 64 == 0 {
+                let entry = unsafe { self.bitmap_start.add(start / 64).read_volatile() };
+                if entry == u64::MAX {
+                    start = (start + 64).next_multiple_of(align);
+                    continue;
+                }
+            }
+            match (start..start + count)
+                .rev()
+                .find(|&frame| self.is_used(frame))
+            {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    self.mark_range_used(start, count);
+                    let phys_addr = PhysAddr::new((start * 4096) as u64);
+                    return PhysFrame::from_start_address(phys_addr).ok();
+                }
+            }
+        }
+        None
+    }
+    pub fn deallocate_contiguous(&mut self, start: PhysFrame<Size4KiB>, count: usize) {
+        let start_frame = (start.start_address().as_u64() / 4096) as usize;
+        self.mark_range_free(start_frame, count);
+    }
+}
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame_no = (frame.start_address().as_u64() / 4096) as usize;
+        self.mark_freed(frame_no);
+    }
+}
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_order(0)
+    }
+}
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_order(9)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
+    }
+}
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_order(start, 9);
     }
 }
@@ -0,0 +1,126 @@
+pub const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+pub const SCN_MEM_WRITE: u32 = 0x8000_0000;
+const PE32_PLUS_MAGIC: u16 = 0x20B;
+const DIRECTORY_BASE_RELOC: usize = 5;
+const REL_BASED_ABSOLUTE: u16 = 0;
+const REL_BASED_DIR64: u16 = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeSection {
+    pub name: [u8; 8],
+    pub virtual_address: u64,
+    pub virtual_size: u64,
+    pub characteristics: u32,
+}
+impl PeSection {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(8);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & SCN_MEM_EXECUTE != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & SCN_MEM_WRITE != 0
+    }
+}
+
+// Walks the section table of a PE/COFF image that has been loaded at `base`.
+#[derive(Clone)]
+pub struct PeSections {
+    table: *const u8,
+    count: usize,
+    index: usize,
+}
+// Returns the COFF file header of the image loaded at `base`.
+unsafe fn coff_header(base: u64) -> Result<*const u8, &'static str> {
+    let image = base as *const u8;
+    unsafe {
+        if image.cast::<[u8; 2]>().read_unaligned() != *b"MZ" {
+            return Err("Missing DOS header");
+        }
+        let pe_offset = image.add(0x3C).cast::<u32>().read_unaligned() as usize;
+        let pe = image.add(pe_offset);
+        if pe.cast::<[u8; 4]>().read_unaligned() != *b"PE\0\0" {
+            return Err("Missing PE signature");
+        }
+        Ok(pe.add(4))
+    }
+}
+
+// Adds `delta` to every absolute address listed in the base relocation table of the image
+// loaded at `base`, moving it to a new virtual address. Returns the number of addresses
+// patched. The image has to be writable at `base`.
+pub unsafe fn apply_relocations(base: u64, delta: u64) -> Result<usize, &'static str> {
+    let image = base as *mut u8;
+    unsafe {
+        let optional = coff_header(base)?.add(20);
+        if optional.cast::<u16>().read_unaligned() != PE32_PLUS_MAGIC {
+            return Err("Not a PE32+ image");
+        }
+        let directory_count = optional.add(108).cast::<u32>().read_unaligned() as usize;
+        if directory_count <= DIRECTORY_BASE_RELOC {
+            return Ok(0);
+        }
+        let directory = optional.add(112 + DIRECTORY_BASE_RELOC * 8);
+        let table = directory.cast::<u32>().read_unaligned() as usize;
+        let table_size = directory.add(4).cast::<u32>().read_unaligned() as usize;
+        let mut patched = 0;
+        let mut offset = 0;
+        while offset + 8 <= table_size {
+            let block = image.add(table + offset);
+            let page = block.cast::<u32>().read_unaligned() as usize;
+            let block_size = block.add(4).cast::<u32>().read_unaligned() as usize;
+            if block_size < 8 || offset + block_size > table_size {
+                return Err("Malformed base relocation block");
+            }
+            for i in 0..(block_size - 8) / 2 {
+                let entry = block.add(8 + i * 2).cast::<u16>().read_unaligned();
+                match entry >> 12 {
+                    REL_BASED_ABSOLUTE => {}
+                    REL_BASED_DIR64 => {
+                        let target = image.add(page + (entry & 0xFFF) as usize).cast::<u64>();
+                        target.write_unaligned(target.read_unaligned().wrapping_add(delta));
+                        patched += 1;
+                    }
+                    _ => return Err("Unsupported base relocation type"),
+                }
+            }
+            offset += block_size;
+        }
+        Ok(patched)
+    }
+}
+
+impl PeSections {
+    pub unsafe fn parse(base: u64) -> Result<Self, &'static str> {
+        unsafe {
+            let coff = coff_header(base)?;
+            let count = coff.add(2).cast::<u16>().read_unaligned() as usize;
+            let optional_size = coff.add(16).cast::<u16>().read_unaligned() as usize;
+            Ok(PeSections {
+                table: coff.add(20 + optional_size),
+                count,
+                index: 0,
+            })
+        }
+    }
+}
+impl Iterator for PeSections {
+    type Item = PeSection;
+    fn next(&mut self) -> Option<PeSection> {
+        if self.index >= self.count {
+            return None;
+        }
+        let header = unsafe { self.table.add(self.index * 40) };
+        self.index += 1;
+        unsafe {
+            Some(PeSection {
+                name: header.cast::<[u8; 8]>().read_unaligned(),
+                virtual_size: header.add(8).cast::<u32>().read_unaligned() as u64,
+                virtual_address: header.add(12).cast::<u32>().read_unaligned() as u64,
+                characteristics: header.add(36).cast::<u32>().read_unaligned(),
+            })
+        }
+    }
+}
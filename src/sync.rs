@@ -0,0 +1,109 @@
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+// How many IRQ-safe sections are currently open, and whether interrupts were enabled
+// before the outermost one. Interrupts only come back on when the last one closes.
+static IRQ_OFF_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static IRQ_WERE_ENABLED: AtomicBool = AtomicBool::new(false);
+// Nesting depth of hardware interrupt handlers currently running
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+struct IrqOff;
+impl IrqOff {
+    fn push() -> Self {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if IRQ_OFF_DEPTH.fetch_add(1, Ordering::SeqCst) == 0 {
+            IRQ_WERE_ENABLED.store(enabled, Ordering::SeqCst);
+        }
+        IrqOff
+    }
+}
+impl Drop for IrqOff {
+    fn drop(&mut self) {
+        if IRQ_OFF_DEPTH.fetch_sub(1, Ordering::SeqCst) == 1
+            && IRQ_WERE_ENABLED.load(Ordering::SeqCst)
+        {
+            interrupts::enable();
+        }
+    }
+}
+
+pub struct InterruptContext;
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+// Marks the caller as running in a hardware interrupt handler until the result is dropped.
+pub fn enter_interrupt() -> InterruptContext {
+    INTERRUPT_DEPTH.fetch_add(1, Ordering::SeqCst);
+    InterruptContext
+}
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::SeqCst) > 0
+}
+
+// Spinlock for state shared with interrupt handlers. Interrupts stay disabled for as long
+// as the guard lives, so a handler can never spin on a lock its own CPU already holds.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+}
+// Fields drop in order, so the lock is released before interrupts are re-enabled
+pub struct IrqMutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    _irq: IrqOff,
+}
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+        }
+    }
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let irq = IrqOff::push();
+        IrqMutexGuard {
+            guard: self.inner.lock(),
+            _irq: irq,
+        }
+    }
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let irq = IrqOff::push();
+        let guard = self.inner.try_lock()?;
+        Some(IrqMutexGuard { guard, _irq: irq })
+    }
+}
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+// Plain spinlock for state that interrupt handlers must never touch. Debug builds panic
+// when it is taken from a handler, since that can deadlock against the interrupted code.
+pub struct Mutex<T> {
+    inner: spin::Mutex<T>,
+}
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            inner: spin::Mutex::new(value),
+        }
+    }
+    fn check_context() {
+        if cfg!(debug_assertions) && in_interrupt() {
+            panic!("non IRQ-safe lock taken in interrupt context");
+        }
+    }
+    pub fn lock(&self) -> spin::MutexGuard<'_, T> {
+        Self::check_context();
+        self.inner.lock()
+    }
+}
@@ -0,0 +1,170 @@
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::apic::cpuid;
+use crate::println;
+use crate::timer;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Timer interrupts since the LAPIC timer started, and the nanoseconds they add up to
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+// Set once the TSC is known to be invariant and has been calibrated
+static USE_TSC: AtomicBool = AtomicBool::new(false);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// A point on the monotonic clock, in nanoseconds since the clock started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+impl Instant {
+    pub fn now() -> Self {
+        if USE_TSC.load(Ordering::Relaxed) {
+            let cycles = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+            let hz = TSC_HZ.load(Ordering::Relaxed);
+            Instant((cycles as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64)
+        } else {
+            Instant(TICK_NANOS.load(Ordering::Relaxed))
+        }
+    }
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(rhs.as_nanos() as u64))
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+fn has_invariant_tsc() -> bool {
+    let (max_extended, _, _) = cpuid(0x8000_0000);
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    let (_, _, edx) = cpuid(0x8000_0007);
+    (edx & (1 << 8)) != 0
+}
+
+// Called from the LAPIC timer interrupt.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let hz = timer::tick_frequency().max(1) as u64;
+    TICK_NANOS.fetch_add(NANOS_PER_SEC / hz, Ordering::Relaxed);
+}
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+pub fn uptime() -> Duration {
+    Duration::from_nanos(Instant::now().as_nanos())
+}
+// Whether Instant::now() advances at all: without an invariant TSC it needs a calibrated
+// LAPIC timer, and a rejected calibration leaves that stopped.
+fn clock_running() -> bool {
+    USE_TSC.load(Ordering::Relaxed) || timer::tick_frequency() > 0
+}
+
+// Spins until `condition` holds or `timeout` passes. Returns whether the condition held.
+// Like udelay, the timeout needs an invariant TSC or interrupts enabled. With no running
+// clock the condition is checked once and the timeout expires at once.
+pub fn poll_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    assert!(
+        USE_TSC.load(Ordering::Relaxed) || interrupts::are_enabled(),
+        "poll_until with interrupts disabled needs an invariant TSC"
+    );
+    if !clock_running() {
+        return condition();
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Busy-waits `us` microseconds. Without an invariant TSC the clock only advances on timer
+// interrupts, so interrupts must be enabled. Returns at once when no clock is running.
+pub fn udelay(us: u64) {
+    assert!(
+        USE_TSC.load(Ordering::Relaxed) || interrupts::are_enabled(),
+        "udelay with interrupts disabled needs an invariant TSC"
+    );
+    if !clock_running() {
+        return;
+    }
+    let deadline = Instant::now() + Duration::from_micros(us);
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+// Halts until `duration` has passed. Falls back to busy-waiting when interrupts are
+// disabled, since nothing would wake the CPU from hlt.
+pub fn sleep(duration: Duration) {
+    if !interrupts::are_enabled() || !clock_running() {
+        udelay(duration.as_micros() as u64);
+        return;
+    }
+    let deadline = Instant::now() + duration;
+    // An interrupt landing between the check and hlt costs at most one timer tick
+    while Instant::now() < deadline {
+        hlt();
+    }
+}
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+// Makes the TSC the clock source when it is invariant, at the rate measured during the
+// LAPIC timer calibration. Otherwise the clock runs off timer ticks.
+pub fn init() {
+    if !has_invariant_tsc() {
+        if timer::tick_frequency() == 0 {
+            println!("[ERROR] No invariant TSC and no LAPIC timer, timeouts expire at once");
+            return;
+        }
+        println!(
+            "[WARN] No invariant TSC, clock resolution is {} Hz timer ticks",
+            timer::tick_frequency()
+        );
+        return;
+    }
+    let Some((hz, reference)) = timer::tsc_calibration() else {
+        println!("[WARN] TSC was not calibrated, clock runs off timer ticks");
+        return;
+    };
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+    USE_TSC.store(true, Ordering::Relaxed);
+    println!(
+        "[OK] Invariant TSC at {}.{:03} MHz ({:?} calibrated)",
+        hz / 1_000_000,
+        hz / 1_000 % 1_000,
+        reference
+    );
+}
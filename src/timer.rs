@@ -0,0 +1,228 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi;
+use crate::apic::{APIC_BASE, read_apic_register, write_apic_register};
+use crate::println;
+use crate::time;
+use crate::vmalloc;
+
+// Default rate of the periodic LAPIC timer interrupt
+pub const TIMER_HZ: u32 = 1000;
+pub const TIMER_VECTOR: u8 = 0x20;
+// How long the LAPIC timer is counted against the reference clock
+const CALIBRATION_MS: u64 = 10;
+// Divide configuration 0x3 is divide by 16
+const TIMER_DIVIDE_CONFIG: u32 = 0x3;
+const TIMER_DIVISOR: u64 = 16;
+const LVT_TIMER: usize = 0x320;
+const INITIAL_COUNT: usize = 0x380;
+const CURRENT_COUNT: usize = 0x390;
+const DIVIDE_CONFIG: usize = 0x3E0;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+
+// Anything outside this range is a broken measurement, not a real LAPIC timer clock
+const MIN_BUS_FREQUENCY: u64 = 1_000_000;
+const MAX_BUS_FREQUENCY: u64 = 10_000_000_000;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_MAX_WAIT_MS: u64 = 54;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+
+const HPET_MMIO_SIZE: u64 = 0x400;
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_COUNTER: usize = 0xF0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reference {
+    Hpet,
+    Pit,
+}
+
+// LAPIC timer input clock (the bus clock on most CPUs) in Hz, before the divider
+static BUS_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TICK_HZ: AtomicU32 = AtomicU32::new(0);
+// TSC rate counted over the same window as the LAPIC timer calibration
+static TSC_CALIBRATION: Once<(u64, Reference)> = Once::new();
+
+pub fn bus_frequency() -> u64 {
+    BUS_FREQUENCY.load(Ordering::Relaxed)
+}
+pub fn tick_frequency() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+pub fn tsc_calibration() -> Option<(u64, Reference)> {
+    TSC_CALIBRATION.get().copied()
+}
+
+// Busy-waits `ms` milliseconds on PIT channel 2 in interrupt-on-terminal-count mode. The
+// channel 2 output is readable through port 0x61, so no PIT interrupt is needed.
+fn pit_wait(ms: u64, start: impl FnOnce()) {
+    // The 16-bit count runs out after about 54.9 ms
+    assert!(ms <= PIT_MAX_WAIT_MS, "PIT wait of {} ms is too long", ms);
+    let count = (PIT_FREQUENCY * ms / 1000) as u16;
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+    unsafe {
+        // Gate on, speaker off
+        let value = (gate.read() & !0x02) | 0x01;
+        gate.write(value);
+        // Channel 2, lobyte/hibyte, mode 0, binary. OUT drops as soon as the mode is set
+        // and only rises again at terminal count.
+        command.write(0xB0);
+        channel2.write(count as u8);
+        // Counting starts once the high byte is in
+        start();
+        channel2.write((count >> 8) as u8);
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct HpetCounter {
+    base: usize,
+    // Counter period in femtoseconds
+    period: u64,
+    mask: u64,
+}
+impl HpetCounter {
+    // Maps the HPET registers and starts the main counter if firmware left it stopped.
+    fn new(address: u64) -> Option<Self> {
+        let base = match vmalloc::ioremap(PhysAddr::new(address), HPET_MMIO_SIZE) {
+            Ok(base) => base.as_u64() as usize,
+            Err(e) => {
+                println!("[WARN] Cannot map HPET registers: {}", e);
+                return None;
+            }
+        };
+        let hpet = HpetCounter {
+            base,
+            period: 0,
+            mask: 0,
+        };
+        let capabilities = hpet.read(HPET_CAPABILITIES);
+        let period = capabilities >> 32;
+        // The spec caps the period at 100 ns
+        if period == 0 || period > 100_000_000 {
+            vmalloc::iounmap(VirtAddr::new(base as u64), HPET_MMIO_SIZE);
+            return None;
+        }
+        let mask = if capabilities & (1 << 13) != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        unsafe {
+            let config = (base + HPET_CONFIG) as *mut u64;
+            config.write_volatile(config.read_volatile() | 1);
+        }
+        Some(HpetCounter {
+            period,
+            mask,
+            ..hpet
+        })
+    }
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { ((self.base + offset) as *const u64).read_volatile() }
+    }
+    fn wait(&self, ms: u64, start: impl FnOnce()) {
+        let ticks = ms * 1_000_000_000_000 / self.period;
+        let begin = self.read(HPET_COUNTER);
+        start();
+        while (self.read(HPET_COUNTER).wrapping_sub(begin) & self.mask) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+static HPET: Once<Option<HpetCounter>> = Once::new();
+
+fn hpet() -> Option<&'static HpetCounter> {
+    HPET.call_once(|| {
+        acpi::info()
+            .and_then(|info| info.hpet)
+            .and_then(|hpet| HpetCounter::new(hpet.address))
+    })
+    .as_ref()
+}
+
+// Busy-waits `ms` milliseconds on the HPET when ACPI reports a usable one, otherwise on
+// PIT channel 2. `start` runs right as the measured interval begins.
+fn reference_wait(ms: u64, start: impl FnOnce()) -> Reference {
+    match hpet() {
+        Some(hpet) => {
+            hpet.wait(ms, start);
+            Reference::Hpet
+        }
+        None => {
+            pit_wait(ms, start);
+            Reference::Pit
+        }
+    }
+}
+
+// Counts the LAPIC timer down from its maximum for CALIBRATION_MS against the reference
+// clock, counting TSC cycles over the same window. Returns the timer input frequency.
+fn calibrate() -> (u64, Reference) {
+    unsafe {
+        write_apic_register(APIC_BASE, DIVIDE_CONFIG, TIMER_DIVIDE_CONFIG);
+        write_apic_register(APIC_BASE, LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    }
+    let mut tsc_begin = 0;
+    let reference = reference_wait(CALIBRATION_MS, || {
+        unsafe { write_apic_register(APIC_BASE, INITIAL_COUNT, u32::MAX) };
+        tsc_begin = time::rdtsc();
+    });
+    let remaining = unsafe { read_apic_register(APIC_BASE, CURRENT_COUNT) };
+    let tsc_end = time::rdtsc();
+    unsafe {
+        write_apic_register(APIC_BASE, INITIAL_COUNT, 0);
+    }
+    let tsc_hz = (tsc_end - tsc_begin) * 1000 / CALIBRATION_MS;
+    TSC_CALIBRATION.call_once(|| (tsc_hz, reference));
+    let elapsed = (u32::MAX - remaining) as u64;
+    (elapsed * TIMER_DIVISOR * 1000 / CALIBRATION_MS, reference)
+}
+
+// Reprograms the periodic LAPIC timer to fire `hz` times a second.
+pub fn set_frequency(hz: u32) {
+    assert!(hz > 0, "LAPIC timer frequency must be non-zero");
+    assert!(bus_frequency() > 0, "LAPIC timer is not calibrated");
+    let count = (bus_frequency() / TIMER_DIVISOR / hz as u64).clamp(1, u32::MAX as u64);
+    TICK_HZ.store(hz, Ordering::Relaxed);
+    unsafe {
+        write_apic_register(APIC_BASE, DIVIDE_CONFIG, TIMER_DIVIDE_CONFIG);
+        write_apic_register(APIC_BASE, LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+        write_apic_register(APIC_BASE, INITIAL_COUNT, count as u32);
+    }
+}
+
+// Calibrates the LAPIC timer and starts it in periodic mode at TIMER_HZ. The timer stays
+// off if the calibration is implausible, since a tiny count would flood the CPU.
+pub fn init() {
+    let (frequency, reference) = calibrate();
+    if !(MIN_BUS_FREQUENCY..=MAX_BUS_FREQUENCY).contains(&frequency) {
+        println!(
+            "[ERROR] LAPIC timer calibration against the {:?} gave {} Hz, timer left off",
+            reference, frequency
+        );
+        return;
+    }
+    BUS_FREQUENCY.store(frequency, Ordering::Relaxed);
+    set_frequency(TIMER_HZ);
+    println!(
+        "[OK] LAPIC timer: {}.{:03} MHz bus ({:?} calibrated), {} Hz tick",
+        frequency / 1_000_000,
+        frequency / 1_000 % 1_000,
+        reference,
+        TIMER_HZ
+    );
+}
@@ -1,142 +1,543 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 use uefi::mem::memory_map::MemoryMap;
-use uefi::mem::memory_map::MemoryType;
 use x86_64::PhysAddr;
-use x86_64::instructions::tlb::flush;
-use x86_64::registers::control::Cr3;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::structures::paging::Mapper;
-use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::mapper::{CleanUp, MapToError, UnmapError};
 use x86_64::structures::paging::{
-    FrameAllocator, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size1GiB, Size2MiB, Size4KiB,
 };
 use x86_64::{VirtAddr, structures::paging::OffsetPageTable};
 
-use crate::memory::FrameAllocatorWrapper;
-use crate::println;
+use crate::apic::cpuid;
+use crate::memory::{FrameAllocatorWrapper, KernelImage, with_frame_allocator};
+use crate::peparser::{PeSections, apply_relocations};
+use crate::{console, println};
 
-pub unsafe fn init(frame_offset: VirtAddr) {}
+// All of physical memory is mapped starting at this address once the kernel page tables
+// are live. Before that the firmware identity map is used, so the offset starts out as 0.
+pub const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+static PHYS_MAP_OFFSET: AtomicU64 = AtomicU64::new(0);
 
-pub fn map_physical_to_virtual(
-    mmap: &uefi::mem::memory_map::MemoryMapOwned,
-    framebuffer_addr: u64,
-    framebuffer_size: usize,
-) {
-    let mut frame_allocator = FrameAllocatorWrapper;
-    let pm4_frame = frame_allocator.allocate_frame().expect("failed to alloc");
-    let phys_addr = pm4_frame.start_address().as_u64();
-    let pm4_frame_ptr = pm4_frame.start_address().as_u64() as *mut PageTable;
-    unsafe {
-        core::ptr::write_bytes(pm4_frame_ptr as *mut u8, 0, 4096);
+pub fn phys_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MAP_OFFSET.load(Ordering::Relaxed))
+}
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    phys_offset() + addr.as_u64()
+}
+
+// The kernel image runs from here once the kernel page tables are live, with every image
+// offset preserved. The firmware load address is only mapped until the kernel has moved
+// onto a stack of its own; see drop_identity_map.
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+
+// Above this many pages a full CR3 reload is cheaper than one invlpg per page
+const INVLPG_THRESHOLD: u64 = 32;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnmapStats {
+    pub pages_unmapped: u64,
+    pub frames_freed: u64,
+    pub tables_freed: u64,
+}
+
+struct CountingDeallocator<'a> {
+    freed: &'a mut u64,
+}
+impl FrameDeallocator<Size4KiB> for CountingDeallocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { FrameAllocatorWrapper.deallocate_frame(frame) };
+        *self.freed += 1;
     }
-    let pm4 = unsafe { &mut *pm4_frame_ptr };
-    let mut mem_map = unsafe { OffsetPageTable::new(pm4, VirtAddr::new(0)) };
+}
 
-    for desc in mmap.entries() {
-        let start_addr = desc.phys_start;
-        let end_addr = start_addr + (desc.page_count * 4096);
-        let start_frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(start_addr));
-        let end_frame = PhysFrame::containing_address(PhysAddr::new(end_addr - 1));
-
-        let mut page_in_region = 0;
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            page_in_region += 1;
-
-            let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            unsafe {
-                match mem_map.map_to(page, frame, flags, &mut frame_allocator) {
-                    Ok(flush) => {
-                        flush.flush();
-                    }
-                    Err(MapToError::PageAlreadyMapped(_)) => {}
-                    Err(MapToError::ParentEntryHugePage) => {}
-                    Err(e) => {
-                        println!(
-                            "[ERROR] Mapping failed at page 0x{:x}: {:?}",
-                            page.start_address().as_u64(),
-                            e
-                        );
-                        panic!("Unknown mapping error {:?}", e);
-                    }
-                }
+pub fn flush_tlb_range(start: VirtAddr, page_count: u64) {
+    if page_count > INVLPG_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for i in 0..page_count {
+            tlb::flush(start + i * 4096);
+        }
+    }
+}
+
+// Unmaps `page_count` 4 KiB pages starting at `start`. Data frames go back to the frame
+// allocator when `free_frames` is set (leave it off for MMIO), and page tables left empty
+// by the unmap are always freed. Pages that are not mapped are skipped, and huge pages
+// are split so only the requested part goes away.
+pub fn unmap_range(start: VirtAddr, page_count: u64, free_frames: bool) -> UnmapStats {
+    let mut stats = UnmapStats::default();
+    if page_count == 0 {
+        return stats;
+    }
+    let mut mem_map = unsafe { active_mapper() };
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = first + (page_count - 1);
+    for page in Page::range_inclusive(first, last) {
+        let mut result = mem_map.unmap(page);
+        if let Err(UnmapError::ParentEntryHugePage) = result {
+            match split_huge_page(
+                &mut mem_map,
+                page.start_address(),
+                &mut FrameAllocatorWrapper,
+            ) {
+                Ok(()) => result = mem_map.unmap(page),
+                Err(e) => println!(
+                    "[ERROR] Cannot split the huge page over 0x{:x}, left mapped: {:?}",
+                    page.start_address().as_u64(),
+                    e
+                ),
             }
         }
+        if let Ok((frame, flush)) = result {
+            // Flushed together below
+            flush.ignore();
+            stats.pages_unmapped += 1;
+            if free_frames {
+                unsafe { FrameAllocatorWrapper.deallocate_frame(frame) };
+                stats.frames_freed += 1;
+            }
+        }
+    }
+    let mut deallocator = CountingDeallocator {
+        freed: &mut stats.tables_freed,
+    };
+    unsafe {
+        mem_map.clean_up_addr_range(Page::range_inclusive(first, last), &mut deallocator);
     }
+    flush_tlb_range(first.start_address(), page_count);
+    stats
+}
 
-    let fb_start = framebuffer_addr;
-    let fb_end = framebuffer_addr + framebuffer_size as u64;
-    let fb_start_frame: PhysFrame<Size4KiB> =
-        PhysFrame::containing_address(PhysAddr::new(fb_start));
-    let fb_end_frame: PhysFrame<Size4KiB> =
-        PhysFrame::containing_address(PhysAddr::new(fb_end - 1));
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    let (pm4_frame, _) = Cr3::read();
+    let pm4_ptr = phys_to_virt(pm4_frame.start_address()).as_mut_ptr::<PageTable>();
+    unsafe { OffsetPageTable::new(&mut *pm4_ptr, phys_offset()) }
+}
 
-    for frame in PhysFrame::range_inclusive(fb_start_frame, fb_end_frame) {
-        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+fn map_region(
+    mem_map: &mut OffsetPageTable,
+    virt_start: u64,
+    phys_start: u64,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocatorWrapper,
+    name: &str,
+) {
+    let start_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(phys_start));
+    let end_frame = PhysFrame::containing_address(PhysAddr::new(phys_start + size - 1));
+    let page_offset = virt_start - start_frame.start_address().as_u64();
 
-        unsafe {
-            match mem_map.map_to(page, frame, flags, &mut frame_allocator) {
-                Ok(flush) => {
-                    flush.flush();
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page =
+            Page::containing_address(VirtAddr::new(frame.start_address().as_u64() + page_offset));
+        let result = loop {
+            match unsafe { mem_map.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => break Ok(flush),
+                // Typically MMIO or the kernel image inside the direct map: the frame is
+                // right, only the caching and permission flags have to change
+                Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {
+                    break unsafe { mem_map.update_flags(page, flags) }
+                        .map_err(|_| MapToError::PageAlreadyMapped(mapped));
                 }
-                Err(MapToError::PageAlreadyMapped(_)) => {}
-                Err(e) => {
-                    println!(
-                        "[ERROR] Failed to map framebuffer page 0x{:x}: {:?}",
-                        page.start_address().as_u64(),
-                        e
-                    );
-                    panic!("Framebuffer mapping failed");
+                Err(MapToError::ParentEntryHugePage) => {
+                    if let Err(e) = split_huge_page(mem_map, page.start_address(), frame_allocator)
+                    {
+                        break Err(e);
+                    }
                 }
+                Err(e) => break Err(e),
+            }
+        };
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                println!(
+                    "[ERROR] Failed to map {} page 0x{:x}: {:?}",
+                    name,
+                    page.start_address().as_u64(),
+                    e
+                );
+                panic!("{} mapping failed", name);
             }
         }
     }
+}
 
-    let lapic_base = 0xFEE00000u64;
-    let lapic_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(lapic_base));
-    let lapic_page = Page::containing_address(VirtAddr::new(lapic_base));
-    let lapic_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
-
-    unsafe {
-        match mem_map.map_to(lapic_page, lapic_frame, lapic_flags, &mut frame_allocator) {
-            Ok(flush) => {
-                flush.flush();
-            }
-            Err(MapToError::PageAlreadyMapped(_)) => {
-                println!("[DEBUG] Local APIC already mapped");
+// Breaks the 1 GiB and 2 MiB pages covering `addr` into smaller ones until `addr` has a
+// 4 KiB entry of its own. Every address keeps its frame and flags.
+fn split_huge_page(
+    mem_map: &mut OffsetPageTable,
+    addr: VirtAddr,
+    frame_allocator: &mut FrameAllocatorWrapper,
+) -> Result<(), MapToError<Size4KiB>> {
+    let offset = mem_map.phys_offset();
+    let p4_entry = &mem_map.level_4_table()[addr.p4_index()];
+    if p4_entry.is_unused() {
+        return Ok(());
+    }
+    let mut table = (offset + p4_entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+    for (index, page_size) in [
+        (addr.p3_index(), Size1GiB::SIZE),
+        (addr.p2_index(), Size2MiB::SIZE),
+    ] {
+        let entry = unsafe { &mut (&mut *table)[index] };
+        if entry.is_unused() {
+            return Ok(());
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let new_frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let new_table = (offset + new_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+            let child_size = page_size / 512;
+            let mut child_flags = entry.flags();
+            if child_size == Size4KiB::SIZE {
+                child_flags.remove(PageTableFlags::HUGE_PAGE);
             }
-            Err(e) => {
-                println!("[ERROR] Failed to map Local APIC: {:?}", e);
-                panic!("Local APIC mapping failed");
+            for (i, child) in unsafe { (&mut *new_table).iter_mut() }.enumerate() {
+                child.set_addr(entry.addr() + i as u64 * child_size, child_flags);
             }
+            // The restrictive flags now live in the new entries
+            entry.set_addr(
+                new_frame.start_address(),
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+            tlb::flush_all();
         }
+        table = (offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>();
     }
+    Ok(())
+}
 
-    let ioapic_base = 0xFEC00000u64;
-    let ioapic_frame: PhysFrame<Size4KiB> =
-        PhysFrame::containing_address(PhysAddr::new(ioapic_base));
-    let ioapic_page = Page::containing_address(VirtAddr::new(ioapic_base));
-    let ioapic_flags =
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+#[derive(Default)]
+struct DirectMapStats {
+    pages_1g: u64,
+    pages_2m: u64,
+    pages_4k: u64,
+}
+impl DirectMapStats {
+    // Page-table frames a pure 4 KiB mapping would have needed on top of ours: every 2 MiB
+    // page replaces a P1 table and every 1 GiB page replaces a P2 table and its 512 P1s.
+    fn saved_table_frames(&self) -> u64 {
+        self.pages_2m + self.pages_1g * 513
+    }
+}
 
-    unsafe {
-        match mem_map.map_to(
-            ioapic_page,
-            ioapic_frame,
-            ioapic_flags,
+fn supports_2mib_pages() -> bool {
+    let (_, _, edx) = cpuid(1);
+    (edx & (1 << 3)) != 0
+}
+fn supports_1gib_pages() -> bool {
+    let (max_extended, _, _) = cpuid(0x8000_0000);
+    if max_extended < 0x8000_0001 {
+        return false;
+    }
+    let (_, _, edx) = cpuid(0x8000_0001);
+    (edx & (1 << 26)) != 0
+}
+
+fn map_huge<S: PageSize + core::fmt::Debug>(
+    mem_map: &mut OffsetPageTable,
+    virt: u64,
+    phys: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocatorWrapper,
+) -> bool
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(VirtAddr::new(virt));
+    let frame = PhysFrame::<S>::containing_address(PhysAddr::new(phys));
+    match unsafe { mem_map.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(MapToError::PageAlreadyMapped(_)) | Err(MapToError::ParentEntryHugePage) => false,
+        Err(e) => {
+            println!(
+                "[ERROR] Failed to map direct map page 0x{:x}: {:?}",
+                virt, e
+            );
+            panic!("direct map mapping failed");
+        }
+    }
+}
+
+// Maps [phys_start, phys_start + size) at PHYS_OFFSET using the largest page size that
+// fits, dropping to 4 KiB pages only at unaligned edges.
+fn map_direct_range(
+    mem_map: &mut OffsetPageTable,
+    phys_start: u64,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut FrameAllocatorWrapper,
+    stats: &mut DirectMapStats,
+) {
+    let use_2m = supports_2mib_pages();
+    let use_1g = supports_1gib_pages();
+    let end = phys_start + size;
+    let mut phys = phys_start;
+    while phys < end {
+        let remaining = end - phys;
+        if use_1g
+            && phys.is_multiple_of(Size1GiB::SIZE)
+            && remaining >= Size1GiB::SIZE
+            && map_huge::<Size1GiB>(mem_map, PHYS_OFFSET + phys, phys, flags, frame_allocator)
+        {
+            stats.pages_1g += 1;
+            phys += Size1GiB::SIZE;
+            continue;
+        }
+        if use_2m
+            && phys.is_multiple_of(Size2MiB::SIZE)
+            && remaining >= Size2MiB::SIZE
+            && map_huge::<Size2MiB>(mem_map, PHYS_OFFSET + phys, phys, flags, frame_allocator)
+        {
+            stats.pages_2m += 1;
+            phys += Size2MiB::SIZE;
+            continue;
+        }
+        if map_huge::<Size4KiB>(mem_map, PHYS_OFFSET + phys, phys, flags, frame_allocator) {
+            stats.pages_4k += 1;
+        }
+        phys += Size4KiB::SIZE;
+    }
+}
+
+// Permissions for the image page at `offset`, taken from the PE sections covering it:
+// code is read-only and executable, everything else is non-executable and only writable
+// where a writable section lives.
+fn image_page_flags(sections: &PeSections, offset: u64) -> PageTableFlags {
+    let mut writable = false;
+    let mut executable = false;
+    for section in sections.clone() {
+        let section_end = section.virtual_address + section.virtual_size;
+        if section.virtual_address < offset + 4096 && section_end > offset {
+            writable |= section.is_writable();
+            executable |= section.is_executable();
+        }
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Maps the kernel image page by page at KERNEL_BASE and, until the switch to the higher
+// half is complete, at its load address too.
+fn map_kernel_image(
+    mem_map: &mut OffsetPageTable,
+    image: KernelImage,
+    frame_allocator: &mut FrameAllocatorWrapper,
+) {
+    let sections = match unsafe { PeSections::parse(image.base) } {
+        Ok(sections) => Some(sections),
+        Err(e) => {
+            println!("[WARN] Cannot parse kernel image ({}), mapping it RWX", e);
+            None
+        }
+    };
+    for section in sections.iter().flat_map(|sections| sections.clone()) {
+        if section.is_writable() && section.is_executable() {
+            println!(
+                "[WARN] Kernel section {} is writable and executable",
+                section.name()
+            );
+        }
+    }
+    for offset in (0..image.size).step_by(4096) {
+        let flags = match &sections {
+            Some(sections) => image_page_flags(sections, offset),
+            None => PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        };
+        for virt in [KERNEL_BASE, image.base] {
+            map_region(
+                mem_map,
+                virt + offset,
+                image.base + offset,
+                4096,
+                flags,
+                frame_allocator,
+                "kernel image",
+            );
+        }
+    }
+}
+
+// Patches the image's absolute addresses for KERNEL_BASE. Writes go through the direct
+// map, since the image mappings themselves keep code and read-only data unwritable.
+fn relocate_kernel(image: KernelImage) {
+    let alias = phys_to_virt(PhysAddr::new(image.base)).as_u64();
+    match unsafe { apply_relocations(alias, KERNEL_BASE.wrapping_sub(image.base)) } {
+        Ok(patched) => println!(
+            "[OK] Kernel relocated to {:#x} ({} addresses patched)",
+            KERNEL_BASE, patched
+        ),
+        Err(e) => panic!("Cannot relocate the kernel image: {}", e),
+    }
+}
+
+// Gives the image's direct-map alias the image's own permissions, never executable, so
+// code and read-only data cannot be written through PHYS_OFFSET either. Runs after the
+// relocations, which are the last writes that go through the alias.
+fn protect_image_alias(image: KernelImage) {
+    let sections = match unsafe { PeSections::parse(image.base) } {
+        Ok(sections) => sections,
+        Err(e) => {
+            println!(
+                "[WARN] Cannot parse kernel image ({}), alias stays writable",
+                e
+            );
+            return;
+        }
+    };
+    let mut mem_map = unsafe { active_mapper() };
+    let mut frame_allocator = FrameAllocatorWrapper;
+    for offset in (0..image.size).step_by(4096) {
+        let flags = image_page_flags(&sections, offset) | PageTableFlags::NO_EXECUTE;
+        map_region(
+            &mut mem_map,
+            phys_to_virt(PhysAddr::new(image.base + offset)).as_u64(),
+            image.base + offset,
+            4096,
+            flags,
             &mut frame_allocator,
-        ) {
-            Ok(flush) => {
-                flush.flush();
-            }
-            Err(MapToError::PageAlreadyMapped(_)) => {
-                println!("[DEBUG] I/O APIC already mapped");
-            }
-            Err(e) => {
-                println!("[ERROR] Failed to map I/O APIC: {:?}", e);
-                panic!("I/O APIC mapping failed");
+            "kernel image alias",
+        );
+    }
+}
+
+// Continues in the higher half by calling the KERNEL_BASE alias of `entry`. The current
+// stack keeps being used, so the boot stack must still be identity mapped.
+pub unsafe fn enter_higher_half(image: KernelImage, entry: extern "C" fn() -> !) -> ! {
+    let addr = entry as usize as u64;
+    let high = if addr >= KERNEL_BASE {
+        addr
+    } else {
+        addr - image.base + KERNEL_BASE
+    };
+    let entry: extern "C" fn() -> ! = unsafe { core::mem::transmute(high as usize) };
+    entry()
+}
+
+// Removes the load-address alias of the kernel image and the boot stack mapping, leaving
+// the lower half empty. Only valid once nothing runs from or points into either.
+pub fn drop_identity_map(image: KernelImage, boot_stack: (u64, u64)) {
+    let image_pages = unmap_range(VirtAddr::new(image.base), image.size.div_ceil(4096), false);
+    let stack_pages = unmap_range(
+        VirtAddr::new(boot_stack.0),
+        (boot_stack.1 - boot_stack.0) / 4096,
+        false,
+    );
+    println!(
+        "[OK] Dropped the identity map ({} pages, {} page tables freed)",
+        image_pages.pages_unmapped + stack_pages.pages_unmapped,
+        image_pages.tables_freed + stack_pages.tables_freed
+    );
+}
+
+// Builds the kernel page tables: every memory map entry, the framebuffer and the LAPIC go
+// into the direct map at PHYS_OFFSET and the kernel image goes to KERNEL_BASE. The image
+// and the boot stack are also identity mapped for now, since we are still executing from
+// their load addresses, and the image is relocated for KERNEL_BASE once the tables are live.
+// Its direct-map alias is then made read-only where the image is.
+pub fn map_physical_to_virtual(
+    mmap: &uefi::mem::memory_map::MemoryMapOwned,
+    framebuffer_addr: u64,
+    framebuffer_size: usize,
+    image: KernelImage,
+    boot_stack: (u64, u64),
+    lapic_phys: u64,
+) {
+    // NX bits are reserved until EFER.NXE is set, and supervisor writes only honour
+    // read-only pages with CR0.WP
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    let mut frame_allocator = FrameAllocatorWrapper;
+    let pm4_frame = frame_allocator.allocate_frame().expect("failed to alloc");
+    let pm4_frame_ptr = pm4_frame.start_address().as_u64() as *mut PageTable;
+    unsafe {
+        core::ptr::write_bytes(pm4_frame_ptr as *mut u8, 0, 4096);
+    }
+    let pm4 = unsafe { &mut *pm4_frame_ptr };
+    let mut mem_map = unsafe { OffsetPageTable::new(pm4, VirtAddr::new(0)) };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut stats = DirectMapStats::default();
+    // Merge adjacent descriptors so the runs are long enough for huge pages
+    let mut run: Option<(u64, u64)> = None;
+    for desc in mmap.entries() {
+        let desc_end = desc.phys_start + desc.page_count * 4096;
+        run = match run {
+            Some((start, end)) if end == desc.phys_start => Some((start, desc_end)),
+            Some((start, end)) => {
+                map_direct_range(
+                    &mut mem_map,
+                    start,
+                    end - start,
+                    flags,
+                    &mut frame_allocator,
+                    &mut stats,
+                );
+                Some((desc.phys_start, desc_end))
             }
-        }
+            None => Some((desc.phys_start, desc_end)),
+        };
+    }
+    if let Some((start, end)) = run {
+        map_direct_range(
+            &mut mem_map,
+            start,
+            end - start,
+            flags,
+            &mut frame_allocator,
+            &mut stats,
+        );
     }
+    map_kernel_image(&mut mem_map, image, &mut frame_allocator);
+    map_region(
+        &mut mem_map,
+        boot_stack.0,
+        boot_stack.0,
+        boot_stack.1 - boot_stack.0,
+        flags,
+        &mut frame_allocator,
+        "boot stack",
+    );
+
+    let mmio_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    map_region(
+        &mut mem_map,
+        PHYS_OFFSET + framebuffer_addr,
+        framebuffer_addr,
+        framebuffer_size as u64,
+        mmio_flags,
+        &mut frame_allocator,
+        "framebuffer",
+    );
+    map_region(
+        &mut mem_map,
+        PHYS_OFFSET + lapic_phys,
+        lapic_phys,
+        4096,
+        mmio_flags,
+        &mut frame_allocator,
+        "Local APIC",
+    );
 
     unsafe {
         x86_64::registers::control::Cr3::write(
@@ -144,15 +545,23 @@ pub fn map_physical_to_virtual(
             x86_64::registers::control::Cr3Flags::empty(),
         );
     }
+    // Nothing may print between the CR3 switch and the framebuffer move below
+    PHYS_MAP_OFFSET.store(PHYS_OFFSET, Ordering::Relaxed);
+    with_frame_allocator(|allocator| allocator.relocate(PHYS_OFFSET));
+    console::relocate_framebuffer(phys_to_virt(PhysAddr::new(framebuffer_addr)).as_u64() as usize);
+    relocate_kernel(image);
+    protect_image_alias(image);
+    println!(
+        "[OK] Direct map: {} x 1GiB, {} x 2MiB, {} x 4KiB pages ({} page-table frames saved)",
+        stats.pages_1g,
+        stats.pages_2m,
+        stats.pages_4k,
+        stats.saved_table_frames()
+    );
 }
 
 pub fn map_heap(heap_start: u64, heap_size: usize) {
-    use crate::memory::FrameAllocatorWrapper;
-
-    let (pm4_frame, _) = Cr3::read();
-    let pm4_ptr = pm4_frame.start_address().as_u64() as *mut PageTable;
-    let pm4 = unsafe { &mut *pm4_ptr };
-    let mut mem_map = unsafe { OffsetPageTable::new(pm4, VirtAddr::new(0)) };
+    let mut mem_map = unsafe { active_mapper() };
 
     let mut frame_allocator = FrameAllocatorWrapper;
 
@@ -170,7 +579,7 @@ pub fn map_heap(heap_start: u64, heap_size: usize) {
             ),
         };
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
 
         unsafe {
             match mem_map.map_to(page, frame, flags, &mut frame_allocator) {
@@ -0,0 +1,202 @@
+use core::fmt;
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::FrameAllocatorWrapper;
+use crate::sync::Mutex;
+use crate::virtualmapper::{active_mapper, phys_to_virt, unmap_range};
+
+// Kernel virtual address window handed out to drivers and subsystems. It sits well above
+// the direct map so the two can never overlap.
+pub const VMALLOC_START: u64 = 0xFFFF_C000_0000_0000;
+pub const VMALLOC_END: u64 = 0xFFFF_D000_0000_0000;
+const MAX_FREE_RANGES: usize = 128;
+// Every area is followed by one unmapped page so running off its end faults
+const GUARD_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum VmError {
+    OutOfVirtualSpace,
+    OutOfMemory,
+    MapFailed(MapToError<Size4KiB>),
+}
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::OutOfVirtualSpace => write!(f, "vmalloc space exhausted"),
+            VmError::OutOfMemory => write!(f, "out of physical frames"),
+            VmError::MapFailed(e) => write!(f, "mapping failed: {:?}", e),
+        }
+    }
+}
+
+struct VirtSpace {
+    // Sorted, non-overlapping [start, end) ranges of unused virtual addresses
+    free: [(u64, u64); MAX_FREE_RANGES],
+    len: usize,
+}
+impl VirtSpace {
+    const fn new() -> Self {
+        let mut free = [(0, 0); MAX_FREE_RANGES];
+        free[0] = (VMALLOC_START, VMALLOC_END);
+        VirtSpace { free, len: 1 }
+    }
+    fn reserve(&mut self, size: u64) -> Option<u64> {
+        let index = (0..self.len).find(|&i| self.free[i].1 - self.free[i].0 >= size)?;
+        let start = self.free[index].0;
+        self.free[index].0 += size;
+        if self.free[index].0 == self.free[index].1 {
+            self.free.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+        Some(start)
+    }
+    fn release(&mut self, start: u64, size: u64) {
+        let end = start + size;
+        let index = (0..self.len)
+            .find(|&i| self.free[i].0 >= end)
+            .unwrap_or(self.len);
+        let merges_prev = index > 0 && self.free[index - 1].1 == start;
+        let merges_next = index < self.len && self.free[index].0 == end;
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.free[index - 1].1 = self.free[index].1;
+                self.free.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+            }
+            (true, false) => self.free[index - 1].1 = end,
+            (false, true) => self.free[index].0 = start,
+            (false, false) => {
+                if self.len == MAX_FREE_RANGES {
+                    // Losing a range only leaks address space, never memory
+                    return;
+                }
+                self.free.copy_within(index..self.len, index + 1);
+                self.free[index] = (start, end);
+                self.len += 1;
+            }
+        }
+    }
+}
+
+static VIRT_SPACE: Mutex<VirtSpace> = Mutex::new(VirtSpace::new());
+
+// Reserves a page-aligned range of kernel virtual addresses without mapping anything.
+pub fn reserve(size: u64) -> Result<VirtAddr, VmError> {
+    let size = size.next_multiple_of(4096);
+    VIRT_SPACE
+        .lock()
+        .reserve(size + GUARD_SIZE)
+        .map(VirtAddr::new)
+        .ok_or(VmError::OutOfVirtualSpace)
+}
+pub fn release(addr: VirtAddr, size: u64) {
+    let size = size.next_multiple_of(4096);
+    VIRT_SPACE.lock().release(addr.as_u64(), size + GUARD_SIZE);
+}
+
+// Maps `page_count` pages starting at `start`, taking frames from `next_frame`. On
+// failure everything mapped so far is torn down again.
+fn map_pages(
+    start: VirtAddr,
+    page_count: u64,
+    flags: PageTableFlags,
+    owns_frames: bool,
+    mut next_frame: impl FnMut(u64) -> Option<PhysFrame<Size4KiB>>,
+) -> Result<(), VmError> {
+    let mut mem_map = unsafe { active_mapper() };
+    let mut frame_allocator = FrameAllocatorWrapper;
+    let first = Page::<Size4KiB>::containing_address(start);
+    for i in 0..page_count {
+        let result = match next_frame(i) {
+            Some(frame) => unsafe {
+                mem_map
+                    .map_to(first + i, frame, flags, &mut frame_allocator)
+                    .map(|flush| flush.flush())
+                    .map_err(|e| {
+                        if owns_frames {
+                            frame_allocator.deallocate_frame(frame);
+                        }
+                        VmError::MapFailed(e)
+                    })
+            },
+            None => Err(VmError::OutOfMemory),
+        };
+        if let Err(e) = result {
+            unmap_range(start, i, owns_frames);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Backs an already reserved range with fresh, zeroed frames.
+pub fn map_fresh(start: VirtAddr, page_count: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    map_pages(
+        start,
+        page_count,
+        flags | PageTableFlags::PRESENT,
+        true,
+        |_| {
+            let frame = FrameAllocatorWrapper.allocate_frame()?;
+            unsafe {
+                core::ptr::write_bytes(
+                    phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                    0,
+                    4096,
+                );
+            }
+            Some(frame)
+        },
+    )
+}
+
+// Allocates a virtually contiguous, zeroed buffer backed by fresh frames.
+pub fn vmalloc(size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmError> {
+    let start = reserve(size)?;
+    match map_fresh(start, size.div_ceil(4096), flags) {
+        Ok(()) => Ok(start),
+        Err(e) => {
+            release(start, size);
+            Err(e)
+        }
+    }
+}
+pub fn vfree(addr: VirtAddr, size: u64) {
+    unmap_range(addr, size.div_ceil(4096), true);
+    release(addr, size);
+}
+
+// Maps a physical MMIO range (e.g. a PCI BAR) uncached and returns the virtual address
+// that corresponds to `phys`, including its offset into the first page.
+pub fn ioremap(phys: PhysAddr, size: u64) -> Result<VirtAddr, VmError> {
+    let page_offset = phys.as_u64() % 4096;
+    let mapped_size = (page_offset + size).next_multiple_of(4096);
+    let start = reserve(mapped_size)?;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    match map_pages(start, mapped_size / 4096, flags, false, |i| {
+        Some(first_frame + i)
+    }) {
+        Ok(()) => Ok(start + page_offset),
+        Err(e) => {
+            release(start, mapped_size);
+            Err(e)
+        }
+    }
+}
+pub fn iounmap(addr: VirtAddr, size: u64) {
+    let page_offset = addr.as_u64() % 4096;
+    let mapped_size = (page_offset + size).next_multiple_of(4096);
+    let start = addr.align_down(4096u64);
+    unmap_range(start, mapped_size / 4096, false);
+    release(start, mapped_size);
+}